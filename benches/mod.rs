@@ -235,6 +235,21 @@ fn large_obj_alloc_gc(b: &mut Bencher) {
         mgr.pop_scope(None, true).unwrap();
     });
 }
+
+#[bench]
+fn large_obj_alloc_reserved_no_gc(b: &mut Bencher) {
+    let mut mgr = init_gc();
+    let kvs = vec![(JsKey::JsSym("0".to_string()), make_num(0.), None)];
+    b.iter(|| {
+        mgr.push_scope(&UNDEF);
+        mgr.reserve(100);
+        for _ in 0..100 {
+            let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+            mgr.alloc(var, Some(ptr)).unwrap();
+        }
+        mgr.pop_scope(None, false).unwrap();
+    });
+}
 // ^^ Large Flat Heap Allocation ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
 // vv Huge Flat Heap Allocation vvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvv
 
@@ -334,6 +349,45 @@ fn mega_load(b: &mut Bencher) {
         mgr.load(&bnd).unwrap();
     });
 }
+#[bench]
+fn wide_deca_load(b: &mut Bencher) {
+    // Every scope on the way holds 100 other bindings, so each step of the walk is a real
+    // hash lookup rather than a probe into an empty map
+    let mut mgr = init_gc();
+    mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+    let var = make_num(0.);
+    let bnd = var.binding.clone();
+    mgr.alloc(var, None).unwrap();
+    for _ in 0..10 {
+        mgr.push_scope(&UNDEF);
+        for i in 0..100 {
+            mgr.alloc(make_num(i as f64), None).unwrap();
+        }
+    }
+    b.iter(|| {
+        mgr.load(&bnd).unwrap();
+    });
+}
+
+#[bench]
+fn wide_deca_load_all(b: &mut Bencher) {
+    let mut mgr = init_gc();
+    mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+    let mut bnds = Vec::new();
+    for _ in 0..10 {
+        mgr.push_scope(&UNDEF);
+        for i in 0..100 {
+            let var = make_num(i as f64);
+            bnds.push(var.binding.clone());
+            mgr.alloc(var, None).unwrap();
+        }
+    }
+    b.iter(|| {
+        for bnd in &bnds {
+            mgr.load(bnd).unwrap();
+        }
+    });
+}
 // ^^ Variable Load Tests ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
 // vv Variable Store Tests vvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvv
 #[bench]
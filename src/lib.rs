@@ -1,46 +1,296 @@
 #![feature(associated_consts)]
 #![feature(box_patterns)]
 #![feature(box_syntax)]
+#![feature(fnbox)]
 #![feature(question_mark)]
+#![feature(try_borrow)]
 //#![feature(plugin)]
 
 //#![plugin(clippy)]
 
+extern crate fnv;
 extern crate jsrs_common;
 
 #[macro_use] extern crate matches;
 
 mod scope;
+mod trace;
 
+use std::boxed::FnBox;
 use std::cell::RefCell;
-use std::collections::hash_map::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::mem;
 use std::rc::Rc;
+use std::result;
 
+use fnv::FnvHashMap;
 use jsrs_common::alloc_box::AllocBox;
 use jsrs_common::ast::Exp;
 use jsrs_common::backend::Backend;
-use jsrs_common::types::js_var::{JsPtrEnum, JsVar};
+use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsPtrTag, JsType, JsVar};
 use jsrs_common::types::binding::{Binding, UniqueBinding};
+use jsrs_common::types::js_obj::JsObjStruct;
+use jsrs_common::types::js_str::JsStrStruct;
 
 use jsrs_common::gc_error::{GcError, Result};
-use scope::{LookupError, Scope, ScopeTag, StoreError};
+use scope::{LookupError, Scope, StoreError};
+
+pub use scope::ScopeTag;
+pub use trace::Traceable;
+
+/// The symbol key a wrapper object made by `ScopeManager::load_boxed` stores its primitive under.
+pub const PRIMITIVE_VALUE_KEY: &'static str = "[[PrimitiveValue]]";
+
+/// How deeply `ScopeManager::render_tree` nests objects before eliding their contents.
+const MAX_RENDER_DEPTH: usize = 16;
+
+/// A single inconsistency found by `ScopeManager::verify_heap`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HeapViolation {
+    pub unique: UniqueBinding,
+    pub kind: ViolationKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// A pointer-typed variable on some stack has no entry in the heap.
+    DanglingStackPtr,
+    /// A local binding maps to a unique binding that is not on its scope's stack.
+    DanglingLocal,
+    /// A child of the given heap object has no entry in the heap.
+    DanglingChild(UniqueBinding),
+}
+
+/// What kind of value a binding holds, as reported by `ScopeManager::binding_kind`.
+#[derive(Clone, Debug)]
+pub enum ValueKind {
+    /// A value stored directly on the stack, such as a number or boolean.
+    Primitive(JsType),
+    /// A pointer into the heap, tagged with the kind of data it points to.
+    Heap(PtrKind),
+}
+
+/// The kind of data stored in a heap entry.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PtrKind {
+    Sym,
+    Str,
+    Obj,
+    Fn,
+}
+
+/// A callback run when the scope it was registered on exits.
+pub type ExitHook = Box<FnBox(&mut ScopeManager)>;
+
+/// A callback run whenever a scope is entered, with the new scope's tag and depth.
+pub type EnterHook = Box<FnMut(&ScopeTag, usize)>;
+
+/// Receives a notification after every successful write to a binding, e.g. to track
+/// dependencies in a reactive layer. Set with `ScopeManager::set_store_observer`.
+pub trait StoreObserver {
+    /// Called after `bnd` changed from `old` to `new`.
+    fn on_store(&mut self, bnd: &Binding, old: &JsVar, new: &JsVar);
+}
+
+/// A heap object detached from its scope by `ScopeManager::detach`. The object stays rooted, and
+/// so survives collection, until this is dropped.
+pub struct DetachedRef {
+    unique: UniqueBinding,
+    heap: Rc<RefCell<AllocBox>>,
+    // The realm's record of detached objects, which this removes itself from when dropped.
+    detached: Rc<RefCell<Vec<UniqueBinding>>>,
+}
+
+impl DetachedRef {
+    /// The unique binding the detached object is stored under in the heap.
+    #[inline]
+    pub fn unique(&self) -> &UniqueBinding {
+        &self.unique
+    }
+}
+
+impl Drop for DetachedRef {
+    fn drop(&mut self) {
+        {
+            let mut detached = self.detached.borrow_mut();
+            if let Some(i) = detached.iter().position(|unique| *unique == self.unique) {
+                detached.swap_remove(i);
+            }
+        }
+        // Unroot the object so the next collection can free it if nothing else refers to it
+        self.heap.borrow_mut().condemn(self.unique.clone()).ok();
+    }
+}
+
+/// The state of the current scope as recorded by `ScopeManager::checkpoint`.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    depth: usize,
+    bindings: HashSet<Binding>,
+}
+
+/// Identifies a realm owned by a `ScopeManager`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RealmId(usize);
+
+/// The state of a realm that is not currently active. Each realm has its own heap, its own
+/// scope stack rooted at its own global scope, and its own stashed closures.
+struct Realm {
+    scopes: Vec<Scope>,
+    closures: FnvHashMap<UniqueBinding, Scope>,
+    closure_parents: FnvHashMap<UniqueBinding, UniqueBinding>,
+    frozen: HashSet<UniqueBinding>,
+    ephemerals: Vec<(usize, Binding, UniqueBinding)>,
+    alloc_tags: FnvHashMap<UniqueBinding, u32>,
+    exit_hooks: Vec<(usize, ExitHook)>,
+    detached: Rc<RefCell<Vec<UniqueBinding>>>,
+    alloc_box: Rc<RefCell<AllocBox>>,
+}
+
+impl Realm {
+    fn new(alloc_box: Rc<RefCell<AllocBox>>) -> Realm {
+        Realm {
+            scopes: vec![Scope::new(ScopeTag::Call, &alloc_box)],
+            closures: FnvHashMap::default(),
+            closure_parents: FnvHashMap::default(),
+            frozen: HashSet::new(),
+            ephemerals: Vec::new(),
+            alloc_tags: FnvHashMap::default(),
+            exit_hooks: Vec::new(),
+            detached: Rc::new(RefCell::new(Vec::new())),
+            alloc_box: alloc_box,
+        }
+    }
+}
 
 pub struct ScopeManager {
     scopes: Vec<Scope>,
-    closures: HashMap<UniqueBinding, Scope>,
+    closures: FnvHashMap<UniqueBinding, Scope>,
+    // Lexical parents of closures, as set by `link_closure_parent`.
+    closure_parents: FnvHashMap<UniqueBinding, UniqueBinding>,
+    frozen: HashSet<UniqueBinding>,
+    // Variables allocated by `alloc_ephemeral` that haven't been swept yet, paired with the depth
+    // of the scope they were allocated into.
+    ephemerals: Vec<(usize, Binding, UniqueBinding)>,
+    // The tags of heap entries allocated while a nonzero tag was set with `set_alloc_tag`.
+    alloc_tags: FnvHashMap<UniqueBinding, u32>,
+    // Scope exit hooks, paired with the depth of the scope they were registered on.
+    exit_hooks: Vec<(usize, ExitHook)>,
+    // Heap objects currently held by a `DetachedRef`, shared with those refs.
+    detached: Rc<RefCell<Vec<UniqueBinding>>>,
     pub alloc_box: Rc<RefCell<AllocBox>>,
+    // Inactive realms, indexed by `RealmId`. The active realm's slot is empty, since its state
+    // lives in the fields above.
+    realms: Vec<Option<Realm>>,
+    realm: RealmId,
+    realm_stack: Vec<RealmId>,
+    last_collection_freed: usize,
+    // The heap size, in entries, above which a collection that frees nothing signals pressure.
+    // Zero means no threshold.
+    heap_threshold: usize,
+    under_pressure: bool,
+    enter_hook: Option<EnterHook>,
+    auto_gc_interval: usize,
+    allocs_since_gc: usize,
+    alloc_tag: u32,
+    // Whether pointer allocations are rejected outright, as set up by `new_stack_only`.
+    stack_only: bool,
+    store_observer: Option<Box<StoreObserver>>,
 }
 
 impl ScopeManager {
     fn new(alloc_box: Rc<RefCell<AllocBox>>) -> ScopeManager {
+        let Realm {
+            scopes, closures, closure_parents, frozen, ephemerals, alloc_tags, exit_hooks, detached,
+            alloc_box
+        } = Realm::new(alloc_box);
         ScopeManager {
-            scopes: vec![Scope::new(ScopeTag::Call, &alloc_box)],
-            closures: HashMap::new(),
+            scopes: scopes,
+            closures: closures,
+            closure_parents: closure_parents,
+            frozen: frozen,
+            ephemerals: ephemerals,
+            alloc_tags: alloc_tags,
+            exit_hooks: exit_hooks,
+            detached: detached,
             alloc_box: alloc_box,
+            realms: vec![None],
+            realm: RealmId(0),
+            realm_stack: Vec::new(),
+            last_collection_freed: 0,
+            heap_threshold: 0,
+            under_pressure: false,
+            enter_hook: None,
+            auto_gc_interval: 0,
+            allocs_since_gc: 0,
+            alloc_tag: 0,
+            stack_only: false,
+            store_observer: None,
+        }
+    }
+
+    /// Create a manager that only holds primitives. Allocating anything that would live in the
+    /// heap fails with `GcError::PtrAlloc`, so the heap is never touched and no collection ever
+    /// has work to do. Loads and stores of primitives behave as they do in a normal manager.
+    pub fn new_stack_only() -> ScopeManager {
+        let mut mgr = ScopeManager::new(Rc::new(RefCell::new(AllocBox::new())));
+        mgr.stack_only = true;
+        mgr
+    }
+
+    /// Create a new realm with its own heap and global scope. The new realm is not entered.
+    pub fn new_realm(&mut self) -> RealmId {
+        let alloc_box = Rc::new(RefCell::new(AllocBox::new()));
+        self.realms.push(Some(Realm::new(alloc_box)));
+        RealmId(self.realms.len() - 1)
+    }
+
+    /// Return the currently active realm.
+    #[inline]
+    pub fn current_realm(&self) -> RealmId {
+        self.realm
+    }
+
+    /// Make a realm active until the matching `exit_realm`. All loads, stores and allocations
+    /// operate on the active realm's heap and scopes only, so bindings from other realms fail to
+    /// resolve. Fails if the realm doesn't exist or is already active.
+    pub fn enter_realm(&mut self, id: RealmId) -> Result<()> {
+        if !self.realms.get(id.0).map_or(false, Option::is_some) {
+            return Err(GcError::Scope);
         }
+        self.realm_stack.push(self.realm);
+        self.switch_realm(id);
+        Ok(())
+    }
+
+    /// Return to the realm that was active before the last `enter_realm`.
+    pub fn exit_realm(&mut self) -> Result<()> {
+        let id = self.realm_stack.pop().ok_or(GcError::Scope)?;
+        self.switch_realm(id);
+        Ok(())
+    }
+
+    fn switch_realm(&mut self, id: RealmId) {
+        let Realm {
+            scopes, closures, closure_parents, frozen, ephemerals, alloc_tags, exit_hooks, detached,
+            alloc_box
+        } = self.realms[id.0].take().expect("Tried to switch to an active realm");
+        let outgoing = Realm {
+            scopes: mem::replace(&mut self.scopes, scopes),
+            closures: mem::replace(&mut self.closures, closures),
+            closure_parents: mem::replace(&mut self.closure_parents, closure_parents),
+            frozen: mem::replace(&mut self.frozen, frozen),
+            ephemerals: mem::replace(&mut self.ephemerals, ephemerals),
+            alloc_tags: mem::replace(&mut self.alloc_tags, alloc_tags),
+            exit_hooks: mem::replace(&mut self.exit_hooks, exit_hooks),
+            detached: mem::replace(&mut self.detached, detached),
+            alloc_box: mem::replace(&mut self.alloc_box, alloc_box),
+        };
+        self.realms[self.realm.0] = Some(outgoing);
+        self.realm = id;
     }
 
-    #[allow(dead_code)]
     #[inline]
     fn curr_scope(&self) -> &Scope {
         self.scopes.last().expect("Tried to access current scope, but none existed")
@@ -64,24 +314,117 @@ impl ScopeManager {
     pub fn push_closure_scope(&mut self, closure: &UniqueBinding) -> Result<()> {
         let closure_scope = self.closures.remove(closure).ok_or(GcError::Scope)?;
         self.scopes.push(closure_scope);
+        self.run_enter_hook();
         Ok(())
     }
 
     pub fn push_scope(&mut self, exp: &Exp) {
-        let tag = match *exp {
-            Exp::Call(..) => ScopeTag::Call,
-            _ => ScopeTag::Block,
-        };
+        match *exp {
+            Exp::Call(..) => self.push_call_scope(),
+            _ => self.push_block_scope(),
+        }
+    }
+
+    /// Push a block scope, which can see its parent's variables, without needing an `Exp`.
+    pub fn push_block_scope(&mut self) {
+        self.push_tagged_scope(ScopeTag::Block);
+    }
+
+    /// Push a function call scope, which is a boundary for variable lookups, without needing an
+    /// `Exp`.
+    pub fn push_call_scope(&mut self) {
+        self.push_tagged_scope(ScopeTag::Call);
+    }
+
+    fn push_tagged_scope(&mut self, tag: ScopeTag) {
         self.scopes.push(Scope::new(tag, &self.alloc_box));
+        self.run_enter_hook();
+    }
+
+    /// Return the tag of the current scope.
+    pub fn current_scope_tag(&self) -> ScopeTag {
+        self.curr_scope().tag.clone()
+    }
+
+    /// Change the tag of the current scope, e.g. to treat a block as a call boundary while
+    /// evaluating strict-mode `eval`. This changes variable resolution for the scope: tagging a
+    /// block as `Call` stops loads and stores from reaching enclosing scopes (other than the
+    /// global scope), and tagging a call as `Block` lets them see the caller's variables. Closure
+    /// scopes are managed by `pop_scope` and `push_closure_scope`, so changing a tag to or from
+    /// `Closure` fails.
+    pub fn retag_current_scope(&mut self, tag: ScopeTag) -> Result<()> {
+        if matches!(tag, ScopeTag::Closure(_)) || matches!(self.curr_scope().tag, ScopeTag::Closure(_)) {
+            return Err(GcError::Scope);
+        }
+        self.curr_scope_mut().tag = tag;
+        Ok(())
+    }
+
+    /// Visit each live scope innermost-first with its depth, tag and local bindings, stopping at
+    /// the first scope for which the visitor returns `Some`. Returns that value, or `None` if the
+    /// visitor never stopped early. Only binding metadata is passed; heap data is not touched.
+    pub fn walk_scopes<R, F>(&self, mut f: F) -> Option<R>
+        where F: FnMut(usize, &ScopeTag, &[Binding]) -> Option<R>
+    {
+        for (depth, scope) in self.scopes.iter().enumerate().rev() {
+            if let Some(res) = f(depth, &scope.tag, &scope.bindings()) {
+                return Some(res);
+            }
+        }
+        None
+    }
+
+    /// Set a callback to run after every scope push, e.g. for tracing. It receives the new
+    /// scope's tag and depth, where the global scope has depth 0. The hook has no access to the
+    /// manager, and so cannot push or pop scopes itself.
+    pub fn set_scope_enter_hook<F>(&mut self, hook: F)
+        where F: FnMut(&ScopeTag, usize) + 'static
+    {
+        self.enter_hook = Some(box hook);
+    }
+
+    fn run_enter_hook(&mut self) {
+        let depth = self.scopes.len() - 1;
+        if let Some(ref mut hook) = self.enter_hook {
+            hook(&self.scopes[depth].tag, depth);
+        }
+    }
+
+    /// Register a callback to run when the current scope exits. Callbacks run in LIFO order at
+    /// the start of `pop_scope`, while the scope is still live, so they may load and store its
+    /// variables. They must leave the scope stack as they found it. Unlike a finalizer, an exit
+    /// hook runs when its scope ends, not when anything is collected.
+    pub fn on_scope_exit(&mut self, hook: ExitHook) {
+        let depth = self.scopes.len() - 1;
+        self.exit_hooks.push((depth, hook));
+    }
+
+    fn run_exit_hooks(&mut self, depth: usize) {
+        while self.exit_hooks.last().map_or(false, |&(d, _)| d >= depth) {
+            let (_, hook) = self.exit_hooks.pop().unwrap();
+            hook(self);
+        }
     }
 
     pub fn pop_scope(&mut self, returning_closure: Option<UniqueBinding>, gc_yield: bool) -> Result<()> {
+        // A closure's scope is stashed back under its own tag when popped, so returning another
+        // closure from it would insert two scopes into `closures` for one pop. Refuse before
+        // touching anything.
+        if returning_closure.is_some() &&
+            self.scopes.last().map_or(false, |scope| matches!(scope.tag, ScopeTag::Closure(_))) {
+            return Err(GcError::Scope);
+        }
+        if !self.scopes.is_empty() {
+            let depth = self.scopes.len() - 1;
+            self.run_exit_hooks(depth);
+        }
         if let Some(mut scope) = self.scopes.pop() {
             // Clean up the dying scope's stack and take ownership of its heap-allocated data for
             // later collection
             if self.scopes.is_empty() {
                 // The global scope was popped and the program is ending.
-                scope.trigger_gc();
+                let freed = scope.trigger_gc();
+                self.record_collection(freed);
                 return Err(GcError::Scope);
             }
             if let Some(unique) = returning_closure {
@@ -95,7 +438,7 @@ impl ScopeManager {
             }
             // Potentially trigger the garbage collector
             if gc_yield {
-                self.curr_scope_mut().trigger_gc();
+                self.collect();
             }
             if let ScopeTag::Closure(unique) = scope.tag.clone() {
                 self.closures.insert(unique.clone(), scope);
@@ -106,176 +449,2750 @@ impl ScopeManager {
         }
     }
 
-    pub fn rename_closure(&mut self, old: &UniqueBinding, new: &UniqueBinding) -> bool {
-        if self.closures.contains_key(old) {
-            let mut scope = self.closures.remove(old).unwrap();
-            scope.tag = ScopeTag::Closure(new.clone());
-            self.closures.insert(new.clone(), scope);
-            true
-        } else {
-            false
+    /// Pop the current scope like `pop_scope`, but leave the global scope in place. Returns
+    /// `Ok(false)` without popping anything if only the global scope is left, so that
+    /// `while mgr.safe_pop_scope(None, false)? {}` unwinds to the top level.
+    pub fn safe_pop_scope(&mut self, returning_closure: Option<UniqueBinding>, gc_yield: bool) -> Result<bool> {
+        if self.scopes.len() <= 1 {
+            return Ok(false);
         }
+        self.pop_scope(returning_closure, gc_yield)?;
+        Ok(true)
     }
-}
 
-impl Backend for ScopeManager {
-    fn alloc(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
-        let binding = var.binding.clone();
-        let is_allocated = self.alloc_box.borrow().is_allocated(&var.unique);
+    /// Run a function against the heap, or return `None` without running it if the heap is
+    /// currently mutably borrowed elsewhere. Most `ScopeManager` methods borrow the heap, so
+    /// holding a borrow of `alloc_box` across a call back into the manager will panic; this
+    /// turns that into a recoverable failure.
+    pub fn with_heap<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&AllocBox) -> R
+    {
+        self.alloc_box.try_borrow().ok().map(|heap| f(&*heap))
+    }
 
-        // If the ptr is already allocated in the heap, just push it onto the stack
-        if is_allocated && ptr.is_some() {
-            self.curr_scope_mut().bind_var(var);
-        } else {
-            self.curr_scope_mut().push_var(var, ptr)?;
+    /// Run a function against the mutable heap, or return `None` without running it if the heap
+    /// is currently borrowed elsewhere.
+    pub fn with_heap_mut<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut AllocBox) -> R
+    {
+        self.alloc_box.try_borrow_mut().ok().map(|mut heap| f(&mut *heap))
+    }
+
+    /// Find the index of the scope a binding resolves to, following the same rules as `load`.
+    fn resolve_scope(&self, bnd: &Binding) -> Option<usize> {
+        for (i, scope) in self.scopes.iter().enumerate().rev() {
+            if scope.contains(bnd) {
+                return Some(i);
+            } else if scope.is_fn_boundary() {
+                break;
+            }
         }
-        Ok(binding)
+        if self.global_scope().contains(bnd) { Some(0) } else { None }
     }
 
-    /// Try to load the variable behind a binding
-    fn load(&mut self, bnd: &Binding) -> Result<(JsVar, Option<JsPtrEnum>)> {
-        let lookup = || {
-            for scope in self.scopes.iter().rev() {
-                match scope.get_var_copy(bnd) {
-                    Ok(v) => { return Ok(v); },
-                    Err(LookupError::FnBoundary) => {
-                        return Err(GcError::Load(bnd.clone()));
-                    },
-                    Err(LookupError::CheckParent) => {},
-                    Err(LookupError::Unreachable) => unreachable!(),
+    /// Return a copy of the variable a binding resolves to, without cloning any heap data.
+    fn resolve_var(&self, bnd: &Binding) -> Option<JsVar> {
+        self.resolve_scope(bnd).and_then(|i| self.scopes[i].get_var(bnd).cloned())
+    }
+
+    /// Return the unique bindings of every heap-allocated value held directly by a heap entry.
+    fn heap_children(&self, unique: &UniqueBinding) -> Vec<UniqueBinding> {
+        let mut children = Vec::new();
+        if let Some(alloc) = self.alloc_box.borrow().find_id(unique) {
+            children.extend(alloc.borrow().children());
+        }
+        children
+    }
+
+    /// Return the unique bindings of every heap entry transitively reachable from a binding,
+    /// including the binding's own heap entry. Returns an empty set if the binding does not
+    /// resolve to a pointer.
+    pub fn reachable_from(&self, bnd: &Binding) -> HashSet<UniqueBinding> {
+        match self.resolve_var(bnd) {
+            Some(JsVar { t: JsType::JsPtr(_), unique, .. }) => self.trace(vec![unique]),
+            _ => HashSet::new(),
+        }
+    }
+
+    /// Return every heap entry reachable from the given heap entries, including themselves.
+    fn trace(&self, roots: Vec<UniqueBinding>) -> HashSet<UniqueBinding> {
+        let mut reachable = HashSet::new();
+        let mut worklist = roots;
+        while let Some(unique) = worklist.pop() {
+            if reachable.contains(&unique) {
+                continue;
+            }
+            worklist.extend(self.heap_children(&unique));
+            reachable.insert(unique);
+        }
+        reachable
+    }
+
+    /// Return the heap entries pointed to directly from the stack of a live scope or a stashed
+    /// closure.
+    fn stack_roots(&self) -> Vec<UniqueBinding> {
+        let mut roots = Vec::new();
+        for scope in self.scopes.iter().chain(self.closures.values()) {
+            for var in scope.vars() {
+                if let JsType::JsPtr(_) = var.t {
+                    roots.push(var.unique.clone());
                 }
             }
-            Err(GcError::Load(bnd.clone()))
-        };
-        match lookup() {
-            Ok(v) => Ok(v),
-            Err(GcError::Load(bnd)) =>
-                self.global_scope().get_var_copy(&bnd)
-                .map_err(|_| GcError::Load(bnd.clone())),
-            _ => unreachable!(),
         }
+        roots
     }
 
-    fn store(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
-        let (mut var, mut ptr) = (var, ptr);
-        let lookup = {
-            let mut res = Err(GcError::Store(var.clone(), ptr.clone()));
-            for ref mut scope in self.scopes.iter_mut().rev() {
-                match scope.update_var(var, ptr) {
-                    Ok(()) => {
-                        res = Ok(());
-                        break;
+    /// Return the stack roots along with every heap entry currently held by a `DetachedRef`.
+    fn roots(&self) -> Vec<UniqueBinding> {
+        let mut roots = self.stack_roots();
+        roots.extend(self.detached.borrow().iter().cloned());
+        roots
+    }
+
+    /// Return every heap entry reachable from a pointer on the stack of a live scope or a stashed
+    /// closure, or from an entry held by a `DetachedRef`.
+    fn live_set(&self) -> HashSet<UniqueBinding> {
+        self.trace(self.roots())
+    }
+
+    /// Return the live heap objects that refer directly to the target, e.g. to find out what is
+    /// keeping it alive.
+    pub fn retainers(&self, target: &UniqueBinding) -> Vec<UniqueBinding> {
+        self.live_set().into_iter()
+            .filter(|unique| self.heap_children(unique).contains(target))
+            .collect()
+    }
+
+    /// Compute the immediate dominator of every live heap entry with the Cooper-Harvey-Kennedy
+    /// algorithm. All roots hang off a virtual entry node, so an entry whose immediate dominator
+    /// is that node (because it is a root, or is reachable from several roots along disjoint
+    /// paths) maps to `None`.
+    fn dominator_tree(&self) -> HashMap<UniqueBinding, Option<UniqueBinding>> {
+        // Number the live graph in postorder from the entry node, which is `None`, recording the
+        // predecessors of every entry along the way
+        let mut order: Vec<Option<UniqueBinding>> = Vec::new();
+        let mut preds: HashMap<UniqueBinding, Vec<Option<UniqueBinding>>> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(Option<UniqueBinding>, Vec<UniqueBinding>)> =
+            vec![(None, self.roots())];
+        while let Some((node, mut children)) = stack.pop() {
+            match children.pop() {
+                Some(child) => {
+                    preds.entry(child.clone()).or_insert_with(Vec::new).push(node.clone());
+                    stack.push((node, children));
+                    if visited.insert(child.clone()) {
+                        let grandchildren = self.heap_children(&child);
+                        stack.push((Some(child), grandchildren));
                     }
-                    Err(StoreError::CheckParent(v, p)) => { var = v; ptr = p; },
-                    Err(StoreError::FnBoundary(v, p)) => {
-                        res = Err(GcError::Store(v, p));
-                        break;
-                    },
-                    Err(StoreError::PtrTypeMismatch) |
-                    Err(StoreError::BadStore) => {
-                        res = Err(GcError::PtrAlloc);
-                        break;
+                },
+                None => order.push(node),
+            }
+        }
+        let post: HashMap<Option<UniqueBinding>, usize> = order.iter().cloned().enumerate()
+            .map(|(i, node)| (node, i))
+            .collect();
+
+        // The entry node comes last in postorder and dominates itself
+        let entry = order.len() - 1;
+        let mut idom: Vec<Option<usize>> = vec![None; order.len()];
+        idom[entry] = Some(entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..entry).rev() {
+                let mut new_idom = None;
+                if let Some(ref node) = order[i] {
+                    for pred in preds.get(node).into_iter().flat_map(|preds| preds.iter()) {
+                        let p = post[pred];
+                        if idom[p].is_some() {
+                            new_idom = Some(new_idom.map_or(p, |other| intersect(&idom, p, other)));
+                        }
+                    }
+                }
+                if new_idom.is_some() && idom[i] != new_idom {
+                    idom[i] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut tree = HashMap::new();
+        for (i, node) in order.iter().enumerate() {
+            if let (&Some(ref unique), Some(d)) = (node, idom[i]) {
+                let dominator = if d == entry { None } else { order[d].clone() };
+                tree.insert(unique.clone(), dominator);
+            }
+        }
+        tree
+    }
+
+    /// Return the live heap entries that every path from a root to the target passes through,
+    /// not counting the target itself, closest to the target first.
+    fn dominators(&self, target: &UniqueBinding) -> Vec<UniqueBinding> {
+        let tree = self.dominator_tree();
+        let mut dominators = Vec::new();
+        let mut next = tree.get(target).cloned().and_then(|idom| idom);
+        while let Some(unique) = next {
+            next = tree.get(&unique).cloned().and_then(|idom| idom);
+            dominators.push(unique);
+        }
+        dominators
+    }
+
+    /// Return the immediate dominator of a live heap entry: the closest entry that every path from
+    /// a root (a scope's stack, a stashed closure or a `DetachedRef`) to the target passes
+    /// through. Freeing the dominator would free the target. Returns `None` if the target is held
+    /// directly by a root, only reachable along disjoint paths, or not live. The dominator tree
+    /// of the whole live heap is computed once per call.
+    pub fn immediate_dominator(&self, target: &UniqueBinding) -> Option<UniqueBinding> {
+        let dominators = self.dominators(target);
+        dominators.into_iter().next()
+    }
+
+    /// Count the live heap entries of each kind: those reachable from a scope, a stashed closure
+    /// or a `DetachedRef`. Garbage awaiting collection is not counted.
+    pub fn heap_histogram(&self) -> HashMap<PtrKind, usize> {
+        let mut histogram = HashMap::new();
+        let heap = self.alloc_box.borrow();
+        for unique in self.live_set() {
+            if let Some(alloc) = heap.find_id(&unique) {
+                let kind = ptr_kind(&*alloc.borrow());
+                *histogram.entry(kind).or_insert(0) += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Call a function once for each live heap entry, in no particular order. Unlike building a
+    /// dump of the whole heap, this lets a consumer stream entries out one at a time. The heap is
+    /// borrowed for the duration, so the callback must not call back into the manager.
+    ///
+    /// `AllocBox` can't enumerate its entries, so this walks `live_set` instead, visiting every
+    /// entry reachable from a scope, a stashed closure or a `DetachedRef`. Garbage awaiting
+    /// collection is skipped.
+    pub fn for_each_heap_entry<F>(&self, mut f: F)
+        where F: FnMut(&UniqueBinding, &JsPtrEnum)
+    {
+        let heap = self.alloc_box.borrow();
+        for unique in self.live_set() {
+            if let Some(alloc) = heap.find_id(&unique) {
+                f(&unique, &*alloc.borrow());
+            }
+        }
+    }
+
+    /// Compare the values behind two bindings structurally. Primitives compare by value, with
+    /// numbers following `Object.is` (so `NaN` equals itself and `-0` differs from `0`), strings
+    /// by their text, and objects by having the same keys with structurally equal values.
+    /// Symbols and functions are only equal to themselves. Shared and cyclic object graphs are
+    /// handled, so two cycles of the same shape compare equal. Fails if either binding doesn't
+    /// resolve.
+    pub fn structural_eq(&self, a: &Binding, b: &Binding) -> Result<bool> {
+        let a = self.resolve_var(a).ok_or_else(|| GcError::Load(a.clone()))?;
+        let b = self.resolve_var(b).ok_or_else(|| GcError::Load(b.clone()))?;
+        let mut assumed = HashSet::new();
+        Ok(self.vars_eq(&a, &b, &mut assumed))
+    }
+
+    /// Compare two variables structurally. Pairs of heap entries already being compared further
+    /// up are assumed equal, which is what lets comparison of cyclic graphs terminate.
+    fn vars_eq(&self, a: &JsVar, b: &JsVar, assumed: &mut HashSet<(UniqueBinding, UniqueBinding)>) -> bool {
+        match (&a.t, &b.t) {
+            (&JsType::JsNum(x), &JsType::JsNum(y)) => same_value(x, y),
+            (&JsType::JsBool(x), &JsType::JsBool(y)) => x == y,
+            (&JsType::JsUndef, &JsType::JsUndef) => true,
+            (&JsType::JsPtr(_), &JsType::JsPtr(_)) => {
+                if a.unique == b.unique || !assumed.insert((a.unique.clone(), b.unique.clone())) {
+                    return true;
+                }
+                let (a_ptr, b_ptr) = {
+                    let heap = self.alloc_box.borrow();
+                    let a_ptr = heap.find_id(&a.unique).map(|alloc| alloc.borrow().clone());
+                    let b_ptr = heap.find_id(&b.unique).map(|alloc| alloc.borrow().clone());
+                    (a_ptr, b_ptr)
+                };
+                match (a_ptr, b_ptr) {
+                    (Some(JsPtrEnum::JsStr(ref x)), Some(JsPtrEnum::JsStr(ref y))) => x.text == y.text,
+                    (Some(JsPtrEnum::JsObj(ref x)), Some(JsPtrEnum::JsObj(ref y))) => {
+                        x.dict.len() == y.dict.len() &&
+                            x.dict.iter().all(|(key, x_var)| {
+                                y.dict.get(key).map_or(false, |y_var| self.vars_eq(x_var, y_var, assumed))
+                            })
                     },
+                    _ => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    /// Set the tag recorded on every heap entry allocated from now on, e.g. to attribute memory
+    /// use to the module doing the allocating. A tag of 0, the default, means untagged.
+    pub fn set_alloc_tag(&mut self, tag: u32) {
+        self.alloc_tag = tag;
+    }
+
+    /// Count the live heap entries allocated under each tag. Untagged entries are counted under
+    /// tag 0.
+    pub fn heap_by_tag(&self) -> HashMap<u32, usize> {
+        let mut counts = HashMap::new();
+        for unique in self.live_set() {
+            let tag = self.alloc_tags.get(&unique).cloned().unwrap_or(0);
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Render the scope stack as an indented tree for debugging, listing each scope's tag and
+    /// bindings and expanding pointers into the heap data they refer to. Each object is expanded
+    /// once and labelled with a number; later references to it, including cyclic ones, print
+    /// that number instead. Objects nested too deeply are elided.
+    pub fn render_tree(&self) -> String {
+        let mut out = String::new();
+        let mut ids = HashMap::new();
+        for (depth, scope) in self.scopes.iter().enumerate() {
+            writeln!(out, "scope {} ({:?})", depth, scope.tag).unwrap();
+            for bnd in scope.bindings() {
+                if let Some(var) = scope.get_var(&bnd) {
+                    write!(out, "  {:?}: ", bnd).unwrap();
+                    self.render_var(&mut out, var, 1, &mut ids);
                 }
             }
-            res
-        };
-        match lookup {
-            Ok(()) => Ok(()),
-            Err(GcError::Store(var, ptr)) =>
-                self.global_scope_mut().update_var(var.clone(), ptr.clone())
-                    .map_err(|_| GcError::Store(var, ptr)),
-            Err(_) => lookup,
         }
-        /*let res = self.curr_scope_mut().update_var(var, ptr);
-        if let Err(GcError::Store(var, ptr)) = res {
-            self.global_scope_mut().update_var(var, ptr)
-        } else {
-            res
-        }*/
+        out
     }
 
-    fn get_alloc_box(&self) -> Rc<RefCell<AllocBox>> {
-        self.alloc_box.clone()
+    fn render_var(&self, out: &mut String, var: &JsVar, indent: usize, ids: &mut HashMap<UniqueBinding, usize>) {
+        if !matches!(var.t, JsType::JsPtr(_)) {
+            writeln!(out, "{:?}", var.t).unwrap();
+            return;
+        }
+        if let Some(id) = ids.get(&var.unique) {
+            writeln!(out, "<object #{}>", id).unwrap();
+            return;
+        }
+        let ptr = self.alloc_box.borrow().find_id(&var.unique).map(|alloc| alloc.borrow().clone());
+        match ptr {
+            Some(JsPtrEnum::JsObj(ref obj)) => {
+                let id = ids.len() + 1;
+                ids.insert(var.unique.clone(), id);
+                if indent >= MAX_RENDER_DEPTH {
+                    writeln!(out, "object #{} {{ ... }}", id).unwrap();
+                    return;
+                }
+                writeln!(out, "object #{}", id).unwrap();
+                for (key, child) in &obj.dict {
+                    for _ in 0..indent + 1 {
+                        out.push_str("  ");
+                    }
+                    write!(out, "{:?}: ", key).unwrap();
+                    self.render_var(out, child, indent + 1, ids);
+                }
+            },
+            Some(JsPtrEnum::JsStr(ref string)) => writeln!(out, "{:?}", string.text).unwrap(),
+            Some(JsPtrEnum::JsSym(_)) => writeln!(out, "symbol").unwrap(),
+            Some(JsPtrEnum::JsFn(_)) => writeln!(out, "function").unwrap(),
+            None => writeln!(out, "<dangling>").unwrap(),
+        }
     }
-}
 
-pub fn init_gc() -> ScopeManager {
-    let alloc_box = Rc::new(RefCell::new(AllocBox::new()));
-    ScopeManager::new(alloc_box)
-}
+    /// Return the indices of all live scopes whose stacks hold the given unique binding, ordered
+    /// from the global scope inwards.
+    pub fn referencing_scopes(&self, unique: &UniqueBinding) -> Vec<usize> {
+        self.scopes.iter().enumerate()
+            .filter(|&(_, scope)| scope.holds(unique))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Return the names of all stashed closures whose captured scopes hold the given unique
+    /// binding.
+    pub fn referencing_closures(&self, unique: &UniqueBinding) -> Vec<UniqueBinding> {
+        self.closures.iter()
+            .filter(|&(_, scope)| scope.holds(unique))
+            .map(|(closure, _)| closure.clone())
+            .collect()
+    }
+
+    /// Whether any live scope or stashed closure holds a variable with the given unique binding.
+    fn is_held(&self, unique: &UniqueBinding) -> bool {
+        self.scopes.iter().chain(self.closures.values()).any(|scope| scope.holds(unique))
+    }
+
+    /// Mark the heap object behind a binding as frozen, so that any later `store` to it fails.
+    /// Freezing is shallow: objects referenced by the frozen object may still be mutated.
+    /// Freezing a primitive is a no-op.
+    pub fn freeze_object(&mut self, bnd: &Binding) -> Result<()> {
+        match self.resolve_var(bnd) {
+            Some(JsVar { t: JsType::JsPtr(_), unique, .. }) => {
+                self.frozen.insert(unique);
+                Ok(())
+            },
+            Some(_) => Ok(()),
+            None => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// Freeze the heap object behind a binding and every object reachable from it.
+    pub fn deep_freeze(&mut self, bnd: &Binding) -> Result<()> {
+        if self.resolve_scope(bnd).is_none() {
+            return Err(GcError::Load(bnd.clone()));
+        }
+        let reachable = self.reachable_from(bnd);
+        self.frozen.extend(reachable);
+        Ok(())
+    }
+
+    /// Whether the heap object behind a binding has been frozen.
+    pub fn is_frozen(&self, bnd: &Binding) -> bool {
+        self.resolve_var(bnd).map_or(false, |var| self.frozen.contains(&var.unique))
+    }
+
+    /// Check the heap for internal consistency. Every pointer on a stack (including the stacks of
+    /// stashed closures) must resolve to a heap entry, every local binding must map to a variable
+    /// on its scope's stack, and every child of a reachable object must itself be allocated.
+    /// This is a debugging tool, and walks the entire reachable heap.
+    pub fn verify_heap(&self) -> result::Result<(), Vec<HeapViolation>> {
+        let mut violations = Vec::new();
+        let mut worklist = Vec::new();
+        for scope in self.scopes.iter().chain(self.closures.values()) {
+            for unique in scope.dangling_locals() {
+                violations.push(HeapViolation { unique: unique, kind: ViolationKind::DanglingLocal });
+            }
+            for var in scope.vars() {
+                if let JsType::JsPtr(_) = var.t {
+                    if self.alloc_box.borrow().is_allocated(&var.unique) {
+                        worklist.push(var.unique.clone());
+                    } else {
+                        violations.push(HeapViolation {
+                            unique: var.unique.clone(),
+                            kind: ViolationKind::DanglingStackPtr,
+                        });
+                    }
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        while let Some(unique) = worklist.pop() {
+            if !seen.insert(unique.clone()) {
+                continue;
+            }
+            for child in self.heap_children(&unique) {
+                if self.alloc_box.borrow().is_allocated(&child) {
+                    worklist.push(child);
+                } else {
+                    violations.push(HeapViolation {
+                        unique: child,
+                        kind: ViolationKind::DanglingChild(unique.clone()),
+                    });
+                }
+            }
+        }
+        if violations.is_empty() { Ok(()) } else { Err(violations) }
+    }
+
+    /// Allocate a variable into the scope at the given depth.
+    fn alloc_at(&mut self, depth: usize, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        if self.stack_only && (ptr.is_some() || matches!(var.t, JsType::JsPtr(_))) {
+            return Err(GcError::PtrAlloc);
+        }
+        let binding = var.binding.clone();
+        let is_allocated = self.alloc_box.borrow().is_allocated(&var.unique);
+
+        // If the ptr is already allocated in the heap, just push it onto the stack
+        if is_allocated && ptr.is_some() {
+            self.scopes[depth].bind_var(var);
+        } else {
+            let tagged = if ptr.is_some() && self.alloc_tag != 0 { Some(var.unique.clone()) } else { None };
+            self.scopes[depth].push_var(var, ptr)?;
+            if let Some(unique) = tagged {
+                self.alloc_tags.insert(unique, self.alloc_tag);
+            }
+        }
+        self.allocs_since_gc += 1;
+        if self.auto_gc_interval > 0 && self.allocs_since_gc >= self.auto_gc_interval {
+            self.collect();
+        }
+        Ok(binding)
+    }
+
+    /// Collect garbage from the current scope's point of view.
+    fn collect(&mut self) {
+        let freed = self.curr_scope_mut().trigger_gc();
+        self.record_collection(freed);
+        self.allocs_since_gc = 0;
+        if !self.alloc_tags.is_empty() {
+            // Forget the tags of anything that was just freed
+            let freed: Vec<UniqueBinding> = {
+                let heap = self.alloc_box.borrow();
+                self.alloc_tags.keys().filter(|unique| !heap.is_allocated(unique)).cloned().collect()
+            };
+            for unique in freed {
+                self.alloc_tags.remove(&unique);
+            }
+        }
+    }
+
+    /// Record the outcome of a collection that freed `freed` heap entries.
+    fn record_collection(&mut self, freed: usize) {
+        self.last_collection_freed = freed;
+        self.under_pressure = freed == 0 && self.heap_threshold > 0 &&
+            self.alloc_box.borrow().len() > self.heap_threshold;
+    }
+
+    /// Set the heap size, in entries, above which `heap_under_pressure` reports a collection that
+    /// freed nothing. A threshold of 0 disables the check. Takes effect from the next collection.
+    pub fn set_heap_threshold(&mut self, entries: usize) {
+        self.heap_threshold = entries;
+    }
+
+    /// Collect garbage automatically after every `every_n_allocs` allocations, in addition to
+    /// collections at scope exit. An interval of 0 disables automatic collection.
+    pub fn set_auto_gc_interval(&mut self, every_n_allocs: usize) {
+        self.auto_gc_interval = every_n_allocs;
+        self.allocs_since_gc = 0;
+    }
+
+    /// Declare a binding in the current scope with an undefined value, as for `var x;`. A later
+    /// `store` to the binding replaces the undefined value.
+    pub fn declare(&mut self, binding: Binding) -> Result<()> {
+        let mut var = JsVar::new(JsType::JsUndef);
+        var.binding = binding;
+        self.alloc(var, None).map(|_| ())
+    }
+
+    /// Allocate a variable directly into the global scope, regardless of the current depth.
+    pub fn alloc_global(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        self.alloc_at(0, var, ptr)
+    }
+
+    /// Check that a variable and its heap data agree: a pointer needs heap data of the matching
+    /// type unless it is already allocated, and a primitive has none.
+    fn check_ptr(&self, var: &JsVar, ptr: &Option<JsPtrEnum>) -> Result<()> {
+        match (&var.t, ptr) {
+            (&JsType::JsPtr(_), &None) if !self.alloc_box.borrow().is_allocated(&var.unique) =>
+                Err(GcError::PtrAlloc),
+            (&JsType::JsPtr(ref tag), &Some(ref ptr)) if !tag.eq_ptr_type(ptr) => Err(GcError::PtrAlloc),
+            (&JsType::JsPtr(_), _) => Ok(()),
+            (_, &Some(_)) => Err(GcError::PtrAlloc),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build an object from all of its properties at once and allocate it into the current scope,
+    /// as for an object literal. Heap-allocated property values are stored by the object itself,
+    /// and no collection can run until the object is rooted in the current scope, so no property
+    /// is ever unreachable from a half-built object. Every entry is checked before anything is
+    /// allocated, so if one is invalid the call fails with `GcError::PtrAlloc` and leaves the heap
+    /// as it was.
+    pub fn alloc_object(&mut self, entries: Vec<(JsKey, JsVar, Option<JsPtrEnum>)>) -> Result<Binding> {
+        if self.stack_only {
+            return Err(GcError::PtrAlloc);
+        }
+        for &(_, ref var, ref ptr) in &entries {
+            self.check_ptr(var, ptr)?;
+        }
+        let var = JsVar::new(JsType::JsPtr(JsPtrTag::JsObj));
+        let obj = {
+            let mut heap = self.alloc_box.borrow_mut();
+            JsObjStruct::new(None, "Object", entries, &mut *heap)
+        };
+        let depth = self.scopes.len() - 1;
+        self.alloc_at(depth, var, Some(JsPtrEnum::JsObj(obj)))
+    }
+
+    /// Allocate a variable like `alloc`, but also return the unique binding its heap data (if
+    /// any) is stored under.
+    pub fn alloc_tracked(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<(Binding, UniqueBinding)> {
+        let unique = var.unique.clone();
+        let binding = self.alloc(var, ptr)?;
+        Ok((binding, unique))
+    }
+
+    /// Load a binding that is expected to hold a number. Fails with `GcError::Load` if the binding
+    /// doesn't exist or holds some other type.
+    pub fn load_num(&mut self, bnd: &Binding) -> Result<f64> {
+        match self.load(bnd)? {
+            (JsVar { t: JsType::JsNum(n), .. }, _) => Ok(n),
+            _ => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// Load a binding that is expected to hold a boolean. Fails with `GcError::Load` if the
+    /// binding doesn't exist or holds some other type.
+    pub fn load_bool(&mut self, bnd: &Binding) -> Result<bool> {
+        match self.load(bnd)? {
+            (JsVar { t: JsType::JsBool(b), .. }, _) => Ok(b),
+            _ => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// Load a binding that is expected to hold a string. Fails with `GcError::Load` if the
+    /// binding doesn't exist or holds some other type.
+    pub fn load_str(&mut self, bnd: &Binding) -> Result<String> {
+        match self.load(bnd)? {
+            (_, Some(JsPtrEnum::JsStr(JsStrStruct { text }))) => Ok(text),
+            _ => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// Load a binding as a heap value, boxing a number or boolean into a `Number` or `Boolean`
+    /// wrapper object for uniform property access. The wrapper holds the primitive under the
+    /// symbol key `PRIMITIVE_VALUE_KEY`. It is allocated into the current scope as an ephemeral,
+    /// so it stays alive only until the next `sweep_ephemerals` (or until its scope is popped);
+    /// the caller must finish with it before then, or store it somewhere reachable. Heap values
+    /// are returned unchanged. Fails with `GcError::Load` if the binding doesn't resolve or holds
+    /// `undefined`, which can't be boxed.
+    pub fn load_boxed(&mut self, bnd: &Binding) -> Result<(JsVar, JsPtrEnum)> {
+        let (var, ptr) = self.load(bnd)?;
+        if let Some(ptr) = ptr {
+            return Ok((var, ptr));
+        }
+        if self.stack_only {
+            return Err(GcError::PtrAlloc);
+        }
+        let name = match var.t {
+            JsType::JsNum(_) => "Number",
+            JsType::JsBool(_) => "Boolean",
+            _ => return Err(GcError::Load(bnd.clone())),
+        };
+        let value = JsVar::new(var.t.clone());
+        let obj = {
+            let mut heap = self.alloc_box.borrow_mut();
+            let kvs = vec![(JsKey::JsSym(PRIMITIVE_VALUE_KEY.to_string()), value, None)];
+            JsObjStruct::new(None, name, kvs, &mut *heap)
+        };
+        let wrapper = JsVar::new(JsType::JsPtr(JsPtrTag::JsObj));
+        let ptr = JsPtrEnum::JsObj(obj);
+        self.alloc_ephemeral(wrapper.clone(), Some(ptr.clone()))?;
+        Ok((wrapper, ptr))
+    }
+
+    /// Look up a property of the string behind a binding: `"length"` gives the number of UTF-16
+    /// code units, and an integer index gives a one-unit string, allocated as an ephemeral in the
+    /// current scope so it only lives until the next `sweep_ephemerals`. Returns `None` for any
+    /// other key or an index past the end. Fails with `GcError::Load` if the binding doesn't
+    /// resolve to a string.
+    pub fn get_string_property(&mut self, bnd: &Binding, key: &JsKey) -> Result<Option<(JsVar, Option<JsPtrEnum>)>> {
+        let text = match self.load(bnd)? {
+            (_, Some(JsPtrEnum::JsStr(JsStrStruct { text }))) => text,
+            _ => return Err(GcError::Load(bnd.clone())),
+        };
+        let units: Vec<u16> = text.encode_utf16().collect();
+        let index = match *key {
+            JsKey::JsStr(ref name) if name.text == "length" => {
+                return Ok(Some((JsVar::new(JsType::JsNum(units.len() as f64)), None)));
+            },
+            // Only the canonical form of an index names a character, so "01" and "+1" don't
+            JsKey::JsStr(ref name) => name.text.parse::<usize>().ok()
+                .and_then(|i| if i.to_string() == name.text { Some(i) } else { None }),
+            JsKey::JsNum(n) if n >= 0. && n.fract() == 0. => Some(n as usize),
+            _ => None,
+        };
+        match index.and_then(|i| units.get(i)) {
+            Some(&unit) => {
+                let var = JsVar::new(JsType::JsPtr(JsPtrTag::JsStr));
+                let ptr = JsPtrEnum::JsStr(JsStrStruct::new(&String::from_utf16_lossy(&[unit])));
+                self.alloc_ephemeral(var.clone(), Some(ptr.clone()))?;
+                Ok(Some((var, Some(ptr))))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve a binding to a heap object and apply a function to it in place. Fails with
+    /// `GcError::Load` if the binding doesn't resolve to an object.
+    fn with_object<R, F>(&self, bnd: &Binding, f: F) -> Result<R>
+        where F: FnOnce(&JsObjStruct) -> R
+    {
+        let var = self.resolve_var(bnd).ok_or_else(|| GcError::Load(bnd.clone()))?;
+        let mut res = None;
+        if let Some(alloc) = self.alloc_box.borrow().find_id(&var.unique) {
+            if let JsPtrEnum::JsObj(ref obj) = *alloc.borrow() {
+                res = Some(f(obj));
+            }
+        }
+        res.ok_or_else(|| GcError::Load(bnd.clone()))
+    }
+
+    /// Like `with_object`, but with mutable access to the object. Fails with `GcError::Store` if
+    /// the object is frozen.
+    fn with_object_mut<R, F>(&mut self, bnd: &Binding, f: F) -> Result<R>
+        where F: FnOnce(&mut JsObjStruct) -> R
+    {
+        let var = self.resolve_var(bnd).ok_or_else(|| GcError::Load(bnd.clone()))?;
+        if self.frozen.contains(&var.unique) {
+            return Err(GcError::Store(var, None));
+        }
+        let mut res = None;
+        if let Some(alloc) = self.alloc_box.borrow().find_id(&var.unique) {
+            if let JsPtrEnum::JsObj(ref mut obj) = *alloc.borrow_mut() {
+                res = Some(f(obj));
+            }
+        }
+        res.ok_or_else(|| GcError::Load(bnd.clone()))
+    }
+
+    /// Remove every property of the object behind a binding in place, leaving it empty. Values
+    /// that were only reachable through the object are condemned, and freed by the next
+    /// collection; values still referenced elsewhere are left alone.
+    pub fn clear_object(&mut self, bnd: &Binding) -> Result<()> {
+        let children = self.with_object_mut(bnd, |obj| {
+            obj.dict.drain()
+                .filter(|&(_, ref var)| matches!(var.t, JsType::JsPtr(_)))
+                .map(|(_, var)| var.unique)
+                .collect()
+        })?;
+        self.release(children);
+        Ok(())
+    }
+
+    /// Return the keys of the object behind a binding, without copying any values. Symbol keys
+    /// are only included if `include_symbols` is set, so that `for...in`-style enumeration can
+    /// skip them. The order of the keys is unspecified.
+    pub fn own_keys(&self, bnd: &Binding, include_symbols: bool) -> Result<Vec<JsKey>> {
+        self.with_object(bnd, |obj| {
+            obj.dict.keys()
+                .filter(|key| include_symbols || !matches!(**key, JsKey::JsSym(_)))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Return the number of keys on the object behind a binding.
+    pub fn own_key_count(&self, bnd: &Binding) -> Result<usize> {
+        self.with_object(bnd, |obj| obj.dict.len())
+    }
+
+    /// Load the value at the end of a chain of object properties, e.g. `a.b.c` is loaded with
+    /// `a` as the root and `[b, c]` as the keys. Only the final value is copied. Fails with
+    /// `GcError::Load` if any intermediate value is not an object or lacks the next key.
+    pub fn load_path(&self, root: &Binding, keys: &[JsKey]) -> Result<(JsVar, Option<JsPtrEnum>)> {
+        let mut var = self.resolve_var(root).ok_or_else(|| GcError::Load(root.clone()))?;
+        for key in keys {
+            let mut next = None;
+            if let Some(alloc) = self.alloc_box.borrow().find_id(&var.unique) {
+                if let JsPtrEnum::JsObj(ref obj) = *alloc.borrow() {
+                    next = obj.dict.get(key).cloned();
+                }
+            }
+            var = next.ok_or_else(|| GcError::Load(root.clone()))?;
+        }
+        let mut ptr = None;
+        if let JsType::JsPtr(_) = var.t {
+            if let Some(alloc) = self.alloc_box.borrow().find_id(&var.unique) {
+                ptr = Some(alloc.borrow().clone());
+            }
+            if ptr.is_none() {
+                return Err(GcError::Load(root.clone()));
+            }
+        }
+        Ok((var, ptr))
+    }
+
+    /// Store a variable, or if its binding doesn't resolve, declare it in the nearest enclosing
+    /// function scope rather than leaving it undeclared. Returns the variable's binding.
+    pub fn store_or_declare_local(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        let binding = var.binding.clone();
+        if self.resolve_scope(&binding).is_some() {
+            self.store(var, ptr)?;
+            Ok(binding)
+        } else {
+            let depth = self.scopes.iter().rposition(Scope::is_fn_boundary).unwrap_or(0);
+            self.alloc_at(depth, var, ptr)
+        }
+    }
+
+    /// Store a batch of variables, returning one result per item in input order. A failed store
+    /// does not abort the rest of the batch.
+    ///
+    /// Each item's binding is resolved once up front, and the items are grouped by the scope they
+    /// resolve to and applied one scope at a time, so the scope chain is never walked again while
+    /// storing. Pointer items still update the heap one at a time, as `store` does. Items in the
+    /// same scope are applied in input order, so later items observe the effects of earlier ones.
+    /// Items that only resolve through a linked closure, or not at all, fall back to `store`.
+    /// Store observers are notified in input order once the batch has been applied.
+    ///
+    /// Per-item errors are the same as `store`'s. The batch as a whole fails with
+    /// `GcError::Scope`, without storing anything, if the heap is currently borrowed elsewhere.
+    pub fn store_batch(&mut self, items: Vec<(JsVar, Option<JsPtrEnum>)>)
+                       -> Result<Vec<Result<()>>> {
+        if self.alloc_box.try_borrow_mut().is_err() {
+            return Err(GcError::Scope);
+        }
+        let linked = !self.linked_parents().is_empty();
+        let mut results: Vec<Option<Result<()>>> = items.iter().map(|_| None).collect();
+        let mut groups: BTreeMap<usize, Vec<(usize, JsVar, Option<JsPtrEnum>)>> = BTreeMap::new();
+        let mut fallback = Vec::new();
+        for (i, (var, ptr)) in items.into_iter().enumerate() {
+            if self.frozen.contains(&var.unique) {
+                results[i] = Some(Err(GcError::Store(var, ptr)));
+                continue;
+            }
+            match self.resolve_scope(&var.binding) {
+                // The global scope is only reached after any linked closures
+                Some(0) if linked => fallback.push((i, var, ptr)),
+                Some(depth) => groups.entry(depth).or_insert_with(Vec::new).push((i, var, ptr)),
+                None => fallback.push((i, var, ptr)),
+            }
+        }
+
+        let observed = self.store_observer.is_some();
+        let mut notices = Vec::new();
+        for (depth, group) in groups {
+            let scope = &mut self.scopes[depth];
+            for (i, var, ptr) in group {
+                let old = if observed { scope.get_var(&var.binding).cloned() } else { None };
+                let new = var.clone();
+                let res = match scope.update_var(var, ptr) {
+                    Ok(()) => Ok(()),
+                    Err(StoreError::CheckParent(v, p)) |
+                    Err(StoreError::FnBoundary(v, p)) => Err(GcError::Store(v, p)),
+                    Err(StoreError::PtrTypeMismatch) |
+                    Err(StoreError::BadStore) => Err(GcError::PtrAlloc),
+                };
+                if let (true, Some(old)) = (res.is_ok(), old) {
+                    notices.push((i, old, new));
+                }
+                results[i] = Some(res);
+            }
+        }
+        for (i, var, ptr) in fallback {
+            let old = if observed { self.observed_var(&var.binding) } else { None };
+            let new = var.clone();
+            let res = self.store_unobserved(var, ptr);
+            if let (true, Some(old)) = (res.is_ok(), old) {
+                notices.push((i, old, new));
+            }
+            results[i] = Some(res);
+        }
+
+        notices.sort_by_key(|&(i, _, _)| i);
+        for (_, old, new) in notices {
+            self.notify_store(&new.binding, &old, &new);
+        }
+        let results = results.into_iter().map(|res| res.expect("every item has a result")).collect();
+        Ok(results)
+    }
+
+    /// Replace the value behind a binding outright, as for `x = {}` after `x = 5`. Unlike
+    /// `store`, the new value may have a different type than the old one. The binding's heap data,
+    /// if any, is condemned unless another scope still holds it. Fails with `GcError::Load` if
+    /// the binding doesn't resolve, or `GcError::PtrAlloc` if `ptr` doesn't match the new type.
+    pub fn reassign(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
+        let depth = self.resolve_scope(&var.binding).ok_or_else(|| GcError::Load(var.binding.clone()))?;
+        if self.stack_only && (ptr.is_some() || matches!(var.t, JsType::JsPtr(_))) {
+            return Err(GcError::PtrAlloc);
+        }
+        self.check_ptr(&var, &ptr)?;
+        let is_allocated = self.alloc_box.borrow().is_allocated(&var.unique);
+        if ptr.is_some() && self.frozen.contains(&var.unique) {
+            return Err(GcError::Store(var, ptr));
+        }
+
+        let observed = if self.store_observer.is_some() { Some(var.clone()) } else { None };
+        let binding = var.binding.clone();
+        let old = if is_allocated {
+            // The new value shares an existing heap entry, which may also need updating. Do that
+            // before touching the scope, so a failure leaves the old binding in place.
+            if let Some(ptr) = ptr {
+                self.alloc_box.borrow_mut().update_ptr(&var.unique, ptr).map_err(|_| GcError::PtrAlloc)?;
+            }
+            let old = self.scopes[depth].remove_var(&binding);
+            self.scopes[depth].bind_var(var);
+            old
+        } else {
+            let old = self.scopes[depth].remove_var(&binding);
+            if let Err(e) = self.scopes[depth].push_var(var, ptr) {
+                // Restore the old binding rather than leave the name unbound
+                self.scopes[depth].remove_var(&binding);
+                if let Some(old) = old {
+                    self.scopes[depth].bind_var(old);
+                }
+                return Err(e);
+            }
+            old
+        };
+        if let (Some(new), Some(old)) = (observed, old.as_ref()) {
+            self.notify_store(&new.binding, old, &new);
+        }
+        if let Some(JsVar { t: JsType::JsPtr(_), unique, .. }) = old {
+            if !self.is_held(&unique) {
+                self.alloc_box.borrow_mut().condemn(unique).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the unique binding of the variable a local binding resolves to.
+    pub fn unique_for(&self, bnd: &Binding) -> Option<UniqueBinding> {
+        self.resolve_var(bnd).map(|var| var.unique)
+    }
+
+    /// Return the local binding of a variable with the given unique binding, searching live
+    /// scopes innermost-first and then stashed closures.
+    pub fn binding_name(&self, unique: &UniqueBinding) -> Option<Binding> {
+        self.scopes.iter().rev().chain(self.closures.values())
+            .filter_map(|scope| scope.get_var_by_unique(unique))
+            .next()
+            .map(|var| var.binding.clone())
+    }
+
+    /// Report whether a binding holds a primitive or a heap pointer, resolving it the same way as
+    /// `load` but without copying any heap data.
+    pub fn binding_kind(&self, bnd: &Binding) -> Result<ValueKind> {
+        let var = match self.resolve_var(bnd) {
+            Some(var) => var,
+            None => return Err(GcError::Load(bnd.clone())),
+        };
+        if !matches!(var.t, JsType::JsPtr(_)) {
+            return Ok(ValueKind::Primitive(var.t));
+        }
+        let kind = self.alloc_box.borrow().find_id(&var.unique)
+            .map(|alloc| ptr_kind(&*alloc.borrow()));
+        kind.map(ValueKind::Heap).ok_or_else(|| GcError::Load(bnd.clone()))
+    }
+
+    /// Return a copy of every variable in the current scope along with its heap data, without
+    /// looking at any parent scopes.
+    pub fn frame_locals(&self) -> Vec<(Binding, JsVar, Option<JsPtrEnum>)> {
+        self.curr_scope().locals_copy()
+    }
+
+    /// Iterate over the current scope's bindings and their variables without copying anything.
+    /// Heap data is not included; it can be loaded through the returned bindings as needed.
+    pub fn current_frame_iter<'a>(&'a self) -> Box<Iterator<Item=(&'a Binding, &'a JsVar)> + 'a> {
+        self.curr_scope().iter()
+    }
+
+    /// Move a variable from the scope it resolves to into the scope at `target_depth`, where the
+    /// global scope has depth 0. The variable's heap data is left untouched. Fails if the target
+    /// doesn't exist or if the move would cross a function boundary.
+    pub fn rebind(&mut self, bnd: &Binding, target_depth: usize) -> Result<()> {
+        let depth = self.resolve_scope(bnd).ok_or_else(|| GcError::Load(bnd.clone()))?;
+        if target_depth >= self.scopes.len() {
+            return Err(GcError::Scope);
+        }
+        let (lo, hi) = if target_depth < depth { (target_depth, depth) } else { (depth, target_depth) };
+        if self.scopes[lo + 1..hi + 1].iter().any(Scope::is_fn_boundary) {
+            return Err(GcError::Scope);
+        }
+        if let Some(var) = self.scopes[depth].remove_var(bnd) {
+            self.scopes[target_depth].bind_var(var);
+        }
+        Ok(())
+    }
+
+    /// Return the bindings captured by a stashed closure, or `None` if there is no such closure.
+    pub fn closure_captures(&self, closure: &UniqueBinding) -> Option<Vec<Binding>> {
+        self.closures.get(closure).map(Scope::bindings)
+    }
+
+    /// Iterate over the stashed closures, each paired with the bindings it has captured.
+    pub fn closures_iter<'a>(&'a self) -> Box<Iterator<Item=(UniqueBinding, Vec<Binding>)> + 'a> {
+        box self.closures.iter().map(|(closure, scope)| (closure.clone(), scope.bindings()))
+    }
+
+    /// Remove a pointer variable from its scope while keeping its heap data alive, e.g. to hand
+    /// it to native code that outlives the scope. The heap data stays rooted until the returned
+    /// `DetachedRef` is dropped. Fails if the binding is not a pointer, or if some other scope
+    /// also holds the variable.
+    pub fn detach(&mut self, bnd: &Binding) -> Result<DetachedRef> {
+        let depth = self.resolve_scope(bnd).ok_or_else(|| GcError::Load(bnd.clone()))?;
+        let var = self.scopes[depth].remove_var(bnd).ok_or_else(|| GcError::Load(bnd.clone()))?;
+        if !matches!(var.t, JsType::JsPtr(_)) {
+            self.scopes[depth].bind_var(var);
+            return Err(GcError::Load(bnd.clone()));
+        }
+        if self.is_held(&var.unique) {
+            self.scopes[depth].bind_var(var);
+            return Err(GcError::Scope);
+        }
+        self.detached.borrow_mut().push(var.unique.clone());
+        Ok(DetachedRef {
+            unique: var.unique,
+            heap: self.alloc_box.clone(),
+            detached: self.detached.clone(),
+        })
+    }
+
+    /// Discard a stashed closure scope. Heap data that was only held by the closure is made
+    /// collectible, but is not freed until the next collection, since other objects may still
+    /// refer to it.
+    pub fn drop_closure(&mut self, closure: &UniqueBinding) -> Result<()> {
+        let scope = self.closures.remove(closure).ok_or(GcError::Scope)?;
+        self.closure_parents.remove(closure);
+        let mut heap = self.alloc_box.borrow_mut();
+        for var in scope.vars() {
+            if let JsType::JsPtr(_) = var.t {
+                if !self.is_held(&var.unique) {
+                    heap.condemn(var.unique.clone()).ok();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove bindings the interpreter knows to be dead from the scopes they resolve to, and
+    /// condemn their heap data if nothing live still refers to it. Returns how many of the
+    /// bindings' heap entries were condemned. Bindings that don't resolve are ignored.
+    ///
+    /// No collection is run: `AllocBox` can only free entries in a full sweep, so condemned
+    /// entries stay in the heap until the next collection frees them.
+    pub fn free_bindings(&mut self, bindings: &[Binding]) -> usize {
+        let mut candidates = Vec::new();
+        for bnd in bindings {
+            if let Some(depth) = self.resolve_scope(bnd) {
+                if let Some(var) = self.scopes[depth].remove_var(bnd) {
+                    if let JsType::JsPtr(_) = var.t {
+                        candidates.push(var.unique);
+                    }
+                }
+            }
+        }
+        self.release(candidates)
+    }
+
+    /// Condemn the heap data of variables that have been removed from their scopes, unless it is
+    /// still live: held by a scope or a `DetachedRef`, or referred to by a live object. Returns
+    /// how many entries were condemned.
+    fn release(&mut self, candidates: Vec<UniqueBinding>) -> usize {
+        let candidates: HashSet<UniqueBinding> = candidates.into_iter().collect();
+        let live = self.live_set();
+        let mut heap = self.alloc_box.borrow_mut();
+        let mut condemned = 0;
+        for unique in candidates {
+            if !live.contains(&unique) && heap.condemn(unique).is_ok() {
+                condemned += 1;
+            }
+        }
+        condemned
+    }
+
+    /// Allocate an interpreter temporary into the current scope. Unlike other variables, it is
+    /// removed from the live scopes by the next `sweep_ephemerals`, even if its scope is still
+    /// active.
+    pub fn alloc_ephemeral(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        let depth = self.scopes.len() - 1;
+        let unique = var.unique.clone();
+        let binding = self.alloc(var, ptr)?;
+        self.ephemerals.push((depth, binding.clone(), unique));
+        Ok(binding)
+    }
+
+    /// Remove every temporary allocated by `alloc_ephemeral` from the live scopes and condemn its
+    /// heap data, for the interpreter to call between statements. Only the binding the temporary
+    /// was allocated under is removed: if the interpreter has since bound it under another name,
+    /// that binding keeps it alive. Temporaries captured by a stashed closure, or reachable from
+    /// a live object or a `DetachedRef`, are kept alive too. Returns how many heap entries were
+    /// condemned.
+    ///
+    /// The sweep is cheap because it doesn't collect: `AllocBox` can only free entries in a full
+    /// sweep, so condemned temporaries stay in the heap until the next collection, whether at a
+    /// scope exit or from the automatic collection interval.
+    pub fn sweep_ephemerals(&mut self) -> usize {
+        let ephemerals = mem::replace(&mut self.ephemerals, Vec::new());
+        let mut candidates = Vec::new();
+        for (depth, binding, unique) in ephemerals {
+            // A temporary whose scope has been popped went with it
+            let scope = match self.scopes.get_mut(depth) {
+                Some(scope) => scope,
+                None => continue,
+            };
+            if scope.get_var(&binding).map_or(true, |var| var.unique != unique) {
+                continue;
+            }
+            if let Some(var) = scope.remove_local(&binding) {
+                if let JsType::JsPtr(_) = var.t {
+                    candidates.push(unique);
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return 0;
+        }
+        self.release(candidates)
+    }
+
+    /// Record the bindings currently in scope, so that allocations made after this point can be
+    /// discarded with `rollback`, e.g. when an expression throws partway through evaluation.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            depth: self.scopes.len() - 1,
+            bindings: self.curr_scope().bindings().into_iter().collect(),
+        }
+    }
+
+    /// Remove every binding added to the checkpointed scope since the checkpoint was taken, and
+    /// condemn their heap data. Heap data that has since become reachable from an older object is
+    /// kept. No collection is run, so the condemned entries are freed by the next one. Stores to
+    /// bindings that existed at the checkpoint are not undone. Fails if the checkpointed scope is
+    /// no longer the current scope.
+    pub fn rollback(&mut self, cp: Checkpoint) -> Result<()> {
+        if cp.depth != self.scopes.len() - 1 {
+            return Err(GcError::Scope);
+        }
+        let mut candidates = Vec::new();
+        for bnd in self.curr_scope().bindings() {
+            if !cp.bindings.contains(&bnd) {
+                if let Some(var) = self.curr_scope_mut().remove_var(&bnd) {
+                    if let JsType::JsPtr(_) = var.t {
+                        candidates.push(var.unique);
+                    }
+                }
+            }
+        }
+        self.release(candidates);
+        Ok(())
+    }
+
+    /// Return the closure whose scope the current function is running in, or `None` if the
+    /// innermost function scope is a plain call (or there is none besides the global scope).
+    /// Block scopes nested inside a closure's scope still count as running in the closure.
+    pub fn current_closure(&self) -> Option<UniqueBinding> {
+        match self.scopes.iter().rev().find(|scope| scope.is_fn_boundary()) {
+            Some(&Scope { tag: ScopeTag::Closure(ref unique), .. }) => Some(unique.clone()),
+            _ => None,
+        }
+    }
+
+    /// Return the depth of the nearest enclosing function scope, i.e. the scope a function call
+    /// (or closure activation) pushed, as opposed to any blocks nested inside it. Returns `None`
+    /// when running top-level code, which is not in any function.
+    pub fn current_function_scope_depth(&self) -> Option<usize> {
+        self.scopes.iter().enumerate().rev()
+            .find(|&(_, scope)| scope.is_fn_boundary())
+            .and_then(|(depth, _)| if depth == 0 { None } else { Some(depth) })
+    }
+
+    /// Whether the current scope is the root scope of a function body, rather than a block nested
+    /// inside one, e.g. to decide where to inject `arguments` and `this`.
+    pub fn in_function_body(&self) -> bool {
+        self.current_function_scope_depth() == Some(self.scopes.len() - 1)
+    }
+
+    /// Record `parent` as the lexically enclosing closure of `child`. When code running in
+    /// `child` looks up or stores to a binding it doesn't define, the parent's captured scope
+    /// (and then the parent's own linked parent, and so on) is searched before the global scope.
+    /// Both closures must exist, either stashed or active, and the link must not create a cycle.
+    pub fn link_closure_parent(&mut self, child: &UniqueBinding, parent: &UniqueBinding) -> Result<()> {
+        if self.closure_scope(child).is_none() || self.closure_scope(parent).is_none() {
+            return Err(GcError::Scope);
+        }
+        let mut ancestor = Some(parent.clone());
+        while let Some(unique) = ancestor {
+            if unique == *child {
+                return Err(GcError::Scope);
+            }
+            ancestor = self.closure_parents.get(&unique).cloned();
+        }
+        self.closure_parents.insert(child.clone(), parent.clone());
+        Ok(())
+    }
+
+    /// Find the scope of a closure, whether it is stashed or currently active.
+    fn closure_scope(&self, closure: &UniqueBinding) -> Option<&Scope> {
+        match self.closures.get(closure) {
+            Some(scope) => Some(scope),
+            None => self.scopes.iter().find(|scope| scope.tag == ScopeTag::Closure(closure.clone())),
+        }
+    }
+
+    fn closure_scope_mut(&mut self, closure: &UniqueBinding) -> Option<&mut Scope> {
+        match self.closures.get_mut(closure) {
+            Some(scope) => Some(scope),
+            None => self.scopes.iter_mut().find(|scope| scope.tag == ScopeTag::Closure(closure.clone())),
+        }
+    }
+
+    /// Return the chain of linked parents of the closure the current function scope belongs to,
+    /// innermost first. Empty if the current function is not a closure or has no linked parent.
+    fn linked_parents(&self) -> Vec<UniqueBinding> {
+        let mut chain = Vec::new();
+        let mut closure = self.current_closure();
+        while let Some(unique) = closure {
+            closure = self.closure_parents.get(&unique).cloned();
+            if let Some(ref parent) = closure {
+                chain.push(parent.clone());
+            }
+        }
+        chain
+    }
+
+    /// Drop every stashed closure that can no longer be called, then collect. A closure is live
+    /// if its function object is reachable from a live scope, from an object held by a
+    /// `DetachedRef`, from a live closure's captured scope, or if it is the linked parent of a
+    /// live closure. Returns how many closures were dropped.
+    pub fn prune_closures(&mut self) -> usize {
+        let mut roots = self.detached.borrow().clone();
+        for scope in &self.scopes {
+            roots.extend(scope.vars()
+                         .filter(|var| matches!(var.t, JsType::JsPtr(_)))
+                         .map(|var| var.unique.clone()));
+        }
+        let mut reachable = self.trace(roots);
+        let mut live = HashSet::new();
+        loop {
+            let newly_live: Vec<UniqueBinding> = self.closures.keys()
+                .filter(|closure| !live.contains(*closure) &&
+                        (reachable.contains(*closure) ||
+                         live.iter().any(|c| self.closure_parents.get(c) == Some(*closure))))
+                .cloned()
+                .collect();
+            if newly_live.is_empty() {
+                break;
+            }
+            let mut roots = Vec::new();
+            for closure in newly_live {
+                if let Some(scope) = self.closures.get(&closure) {
+                    roots.extend(scope.vars()
+                                 .filter(|var| matches!(var.t, JsType::JsPtr(_)))
+                                 .map(|var| var.unique.clone()));
+                }
+                live.insert(closure);
+            }
+            reachable.extend(self.trace(roots));
+        }
+
+        let dead: Vec<UniqueBinding> = self.closures.keys()
+            .filter(|closure| !live.contains(*closure))
+            .cloned()
+            .collect();
+        for closure in &dead {
+            self.drop_closure(closure).ok();
+        }
+        self.collect();
+        dead.len()
+    }
+
+    /// Reserve space for at least `additional` more variables in the current scope. This is only
+    /// a hint to avoid rehashing during a burst of allocations, and the scope can still grow past
+    /// it.
+    pub fn reserve(&mut self, additional: usize) {
+        self.curr_scope_mut().reserve(additional);
+    }
+
+    /// Return the number of entries currently in the heap, including garbage that has not been
+    /// collected yet.
+    pub fn live_object_count(&self) -> usize {
+        self.alloc_box.borrow().len()
+    }
+
+    /// Return the number of heap entries reachable from a live scope, a stashed closure or a
+    /// `DetachedRef`, and the total number of heap entries, without running a collection. The
+    /// difference is roughly how much the next collection would free, which can help decide
+    /// whether one is worthwhile.
+    pub fn heap_liveness(&self) -> (usize, usize) {
+        let reachable = self.live_set().len();
+        (reachable, self.alloc_box.borrow().len())
+    }
+
+    /// Return the total number of variables on the stacks of all live scopes.
+    pub fn total_stack_slots(&self) -> usize {
+        self.scopes.iter().fold(0, |slots, scope| slots + scope.len())
+    }
+
+    /// Return the number of heap entries freed by the most recent collection.
+    #[inline]
+    pub fn last_collection_freed(&self) -> usize {
+        self.last_collection_freed
+    }
+
+    /// Whether the most recent collection freed nothing while the heap was over the threshold set
+    /// with `set_heap_threshold`. This suggests a genuine leak rather than transient pressure, so
+    /// an embedder can raise an out-of-memory error instead of collecting again. Always `false`
+    /// before the first collection or if no threshold is set.
+    #[inline]
+    pub fn heap_under_pressure(&self) -> bool {
+        self.under_pressure
+    }
+
+    /// Fold the current scope into its parent: every binding, primitives included, moves into the
+    /// parent scope, and the emptied scope is popped without a collection. Any exit hooks on the
+    /// current scope are handed to the parent. Fails if a binding is already defined in the
+    /// parent, if the current scope is a closure, or if there is no parent.
+    pub fn merge_into_parent(&mut self) -> Result<()> {
+        if self.scopes.len() < 2 || matches!(self.curr_scope().tag, ScopeTag::Closure(_)) {
+            return Err(GcError::Scope);
+        }
+        let mut scope = self.scopes.pop().unwrap();
+        if let Err(e) = scope.merge_into(self.curr_scope_mut()) {
+            self.scopes.push(scope);
+            return Err(e);
+        }
+        let depth = self.scopes.len();
+        for hook in &mut self.exit_hooks {
+            if hook.0 == depth {
+                hook.0 = depth - 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge any run of empty block scopes at the top of the scope stack into a single scope,
+    /// to save memory under pathological nesting. Exit hooks on the merged scopes move to the
+    /// surviving one. Returns how many scopes were removed; the caller pops that many fewer
+    /// scopes as it leaves the blocks, since the survivor stands in for all of them.
+    pub fn flatten_empty_blocks(&mut self) -> usize {
+        let run = self.scopes.iter().rev()
+            .take_while(|scope| scope.tag == ScopeTag::Block && scope.is_empty())
+            .count();
+        if run < 2 {
+            return 0;
+        }
+        let depth = self.scopes.len() - run;
+        self.scopes.truncate(depth + 1);
+        for hook in &mut self.exit_hooks {
+            if hook.0 > depth {
+                hook.0 = depth;
+            }
+        }
+        run - 1
+    }
+
+    pub fn rename_closure(&mut self, old: &UniqueBinding, new: &UniqueBinding) -> bool {
+        if self.closures.contains_key(old) {
+            let mut scope = self.closures.remove(old).unwrap();
+            scope.tag = ScopeTag::Closure(new.clone());
+            self.closures.insert(new.clone(), scope);
+            if let Some(parent) = self.closure_parents.remove(old) {
+                self.closure_parents.insert(new.clone(), parent);
+            }
+            for parent in self.closure_parents.values_mut() {
+                if *parent == *old {
+                    *parent = new.clone();
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set an observer to be told about every successful `store` and `reassign`, with the
+    /// binding's old and new values. Replaces any previous observer.
+    pub fn set_store_observer(&mut self, observer: Box<StoreObserver>) {
+        self.store_observer = Some(observer);
+    }
+
+    /// Remove the store observer, returning it if there was one.
+    pub fn take_store_observer(&mut self) -> Option<Box<StoreObserver>> {
+        self.store_observer.take()
+    }
+
+    /// Find the variable a store to the given binding would overwrite.
+    fn observed_var(&self, bnd: &Binding) -> Option<JsVar> {
+        if let Some(var) = self.resolve_var(bnd) {
+            return Some(var);
+        }
+        for closure in self.linked_parents() {
+            if let Some(var) = self.closure_scope(&closure).and_then(|scope| scope.get_var(bnd)) {
+                return Some(var.clone());
+            }
+        }
+        None
+    }
+
+    fn notify_store(&mut self, bnd: &Binding, old: &JsVar, new: &JsVar) {
+        if let Some(ref mut observer) = self.store_observer {
+            observer.on_store(bnd, old, new);
+        }
+    }
+
+    /// Store a variable without notifying the store observer.
+    fn store_unobserved(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
+        if self.frozen.contains(&var.unique) {
+            return Err(GcError::Store(var, ptr));
+        }
+        let (mut var, mut ptr) = (var, ptr);
+        let lookup = {
+            let mut res = Err(GcError::Store(var.clone(), ptr.clone()));
+            for ref mut scope in self.scopes.iter_mut().rev() {
+                match scope.update_var(var, ptr) {
+                    Ok(()) => {
+                        res = Ok(());
+                        break;
+                    }
+                    Err(StoreError::CheckParent(v, p)) => { var = v; ptr = p; },
+                    Err(StoreError::FnBoundary(v, p)) => {
+                        res = Err(GcError::Store(v, p));
+                        break;
+                    },
+                    Err(StoreError::PtrTypeMismatch) |
+                    Err(StoreError::BadStore) => {
+                        res = Err(GcError::PtrAlloc);
+                        break;
+                    },
+                }
+            }
+            res
+        };
+        match lookup {
+            Ok(()) => Ok(()),
+            Err(GcError::Store(var, ptr)) => {
+                for closure in self.linked_parents() {
+                    if let Some(scope) = self.closure_scope_mut(&closure) {
+                        if scope.contains(&var.binding) {
+                            return scope.update_var(var, ptr).map_err(|_| GcError::PtrAlloc);
+                        }
+                    }
+                }
+                self.global_scope_mut().update_var(var.clone(), ptr.clone())
+                    .map_err(|_| GcError::Store(var, ptr))
+            },
+            Err(_) => lookup,
+        }
+        /*let res = self.curr_scope_mut().update_var(var, ptr);
+        if let Err(GcError::Store(var, ptr)) = res {
+            self.global_scope_mut().update_var(var, ptr)
+        } else {
+            res
+        }*/
+    }
+}
+
+impl Backend for ScopeManager {
+    fn alloc(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        let depth = self.scopes.len() - 1;
+        self.alloc_at(depth, var, ptr)
+    }
+
+    /// Try to load the variable behind a binding
+    fn load(&mut self, bnd: &Binding) -> Result<(JsVar, Option<JsPtrEnum>)> {
+        let lookup = || {
+            for scope in self.scopes.iter().rev() {
+                match scope.get_var_copy(bnd) {
+                    Ok(v) => { return Ok(v); },
+                    Err(LookupError::FnBoundary) => {
+                        return Err(GcError::Load(bnd.clone()));
+                    },
+                    Err(LookupError::CheckParent) => {},
+                    Err(LookupError::Unreachable) => unreachable!(),
+                }
+            }
+            Err(GcError::Load(bnd.clone()))
+        };
+        match lookup() {
+            Ok(v) => Ok(v),
+            Err(GcError::Load(bnd)) => {
+                // Try the scopes of any lexically enclosing closures before the global scope
+                for closure in self.linked_parents() {
+                    if let Some(scope) = self.closure_scope(&closure) {
+                        if scope.contains(&bnd) {
+                            return scope.get_var_copy(&bnd).map_err(|_| GcError::Load(bnd.clone()));
+                        }
+                    }
+                }
+                self.global_scope().get_var_copy(&bnd)
+                    .map_err(|_| GcError::Load(bnd.clone()))
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn store(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
+        if self.store_observer.is_none() {
+            return self.store_unobserved(var, ptr);
+        }
+        let bnd = var.binding.clone();
+        let old = self.observed_var(&bnd);
+        let new = var.clone();
+        self.store_unobserved(var, ptr)?;
+        if let Some(old) = old {
+            self.notify_store(&bnd, &old, &new);
+        }
+        Ok(())
+    }
+
+    fn get_alloc_box(&self) -> Rc<RefCell<AllocBox>> {
+        self.alloc_box.clone()
+    }
+}
+
+/// The kind of data a heap entry holds.
+fn ptr_kind(ptr: &JsPtrEnum) -> PtrKind {
+    match *ptr {
+        JsPtrEnum::JsSym(_) => PtrKind::Sym,
+        JsPtrEnum::JsStr(_) => PtrKind::Str,
+        JsPtrEnum::JsObj(_) => PtrKind::Obj,
+        JsPtrEnum::JsFn(_) => PtrKind::Fn,
+    }
+}
+
+/// Find the closest common dominator of two nodes in a partially built dominator tree, where
+/// nodes are numbered in postorder and `idom` holds each processed node's immediate dominator.
+fn intersect(idom: &[Option<usize>], a: usize, b: usize) -> usize {
+    let (mut a, mut b) = (a, b);
+    while a != b {
+        while a < b {
+            a = idom[a].expect("Intersected an unprocessed node");
+        }
+        while b < a {
+            b = idom[b].expect("Intersected an unprocessed node");
+        }
+    }
+    a
+}
+
+/// Compare two numbers the way `Object.is` does: by their bits, except that every `NaN` is the
+/// same value.
+fn same_value(x: f64, y: f64) -> bool {
+    if x.is_nan() || y.is_nan() {
+        return x.is_nan() && y.is_nan();
+    }
+    let (x_bits, y_bits): (u64, u64) = unsafe { (mem::transmute(x), mem::transmute(y)) };
+    x_bits == y_bits
+}
+
+pub fn init_gc() -> ScopeManager {
+    let alloc_box = Rc::new(RefCell::new(AllocBox::new()));
+    ScopeManager::new(alloc_box)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use jsrs_common::ast::Exp;
+    use jsrs_common::backend::Backend;
+    use jsrs_common::gc_error::GcError;
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsPtrTag, JsType, JsVar};
+    use jsrs_common::types::binding::Binding;
+    use jsrs_common::types::js_str::JsStrStruct;
+
+    #[test]
+    fn test_push_closure_scope() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+        mgr.push_closure_scope(&unique).unwrap();
+        assert_eq!(mgr.closures.len(), 0);
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+    }
+
+    #[test]
+    fn test_closure_captures_primitive() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let i_bnd = mgr.alloc(test_utils::make_num(3.), None).unwrap();
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+
+        // The loop variable is gone from the caller...
+        assert!(mgr.load(&i_bnd).is_err());
+        // ...but lives on in the closure's environment
+        mgr.push_closure_scope(&unique).unwrap();
+        let (i, ptr) = mgr.load(&i_bnd).unwrap();
+        match i.t {
+            JsType::JsNum(n) => assert!(f64::abs(n - 3.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(ptr.is_none());
+    }
+
+    #[test]
+    fn test_current_function_scope_depth() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        assert_eq!(mgr.current_function_scope_depth(), None);
+        assert!(!mgr.in_function_body());
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.current_function_scope_depth(), None);
+
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        assert_eq!(mgr.current_function_scope_depth(), Some(2));
+        assert!(mgr.in_function_body());
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.current_function_scope_depth(), Some(2));
+        assert!(!mgr.in_function_body());
+    }
+
+    #[test]
+    fn test_current_closure() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        assert_eq!(mgr.current_closure(), None);
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+
+        mgr.push_closure_scope(&unique).unwrap();
+        assert_eq!(mgr.current_closure(), Some(unique.clone()));
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.current_closure(), Some(unique.clone()));
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        assert_eq!(mgr.current_closure(), None);
+        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.current_closure(), None);
+    }
+
+    #[test]
+    fn test_link_closure_parent() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        // The outer function defines `x` and returns the middle closure
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let x_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let middle = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(middle.clone()), false).unwrap();
+
+        // Calling the middle closure runs a function body that returns the inner closure
+        mgr.push_closure_scope(&middle).unwrap();
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let inner = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(inner.clone()), false).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+
+        // Without a link, the inner closure can't see `x`
+        mgr.push_closure_scope(&inner).unwrap();
+        assert!(mgr.load(&x_bnd).is_err());
+        mgr.pop_scope(None, false).unwrap();
+
+        mgr.link_closure_parent(&inner, &middle).unwrap();
+        mgr.push_closure_scope(&inner).unwrap();
+        let (x, _) = mgr.load(&x_bnd).unwrap();
+        assert!(matches!(x.t, JsType::JsNum(n) if f64::abs(n - 1.) < 0.0001));
+        let mut x = x;
+        x.t = JsType::JsNum(2.);
+        mgr.store(x, None).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+
+        mgr.push_closure_scope(&middle).unwrap();
+        let (x, _) = mgr.load(&x_bnd).unwrap();
+        assert!(matches!(x.t, JsType::JsNum(n) if f64::abs(n - 2.) < 0.0001));
+    }
+
+    #[test]
+    fn test_link_closure_parent_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let mut closures = Vec::new();
+        for _ in 0..2 {
+            mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+            let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+            closures.push(fn_var.unique.clone());
+            mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+            mgr.pop_scope(Some(closures[closures.len() - 1].clone()), false).unwrap();
+        }
+        let (fn_var, _) = test_utils::make_fn(&None, &Vec::new());
+        let missing = fn_var.unique;
+
+        assert!(matches!(mgr.link_closure_parent(&closures[0], &missing), Err(GcError::Scope)));
+        assert!(matches!(mgr.link_closure_parent(&closures[0], &closures[0]), Err(GcError::Scope)));
+        mgr.link_closure_parent(&closures[0], &closures[1]).unwrap();
+        assert!(matches!(mgr.link_closure_parent(&closures[1], &closures[0]), Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_realms() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let outer_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+
+        let realm = mgr.new_realm();
+        mgr.enter_realm(realm).unwrap();
+        assert_eq!(mgr.current_realm(), realm);
+        assert!(mgr.load(&outer_bnd).is_err());
+        let (var, ptr) = test_utils::make_str("test");
+        let inner_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 1);
+
+        mgr.exit_realm().unwrap();
+        assert!(mgr.load(&outer_bnd).is_ok());
+        assert!(mgr.load(&inner_bnd).is_err());
+        assert_eq!(mgr.live_object_count(), 0);
+
+        mgr.enter_realm(realm).unwrap();
+        assert!(mgr.load(&inner_bnd).is_ok());
+    }
+
+    #[test]
+    fn test_realms_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        assert!(matches!(mgr.exit_realm(), Err(GcError::Scope)));
+        let realm = mgr.current_realm();
+        assert!(matches!(mgr.enter_realm(realm), Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_unique_for() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let var = test_utils::make_num(1.);
+        let unique = var.unique.clone();
+        let bnd = mgr.alloc(var, None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+
+        assert_eq!(mgr.unique_for(&bnd), Some(unique.clone()));
+        assert_eq!(mgr.binding_name(&unique), Some(bnd));
+        assert!(mgr.unique_for(&Binding::new("".to_owned())).is_none());
+    }
+
+    #[test]
+    fn test_binding_kind() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        assert!(matches!(mgr.binding_kind(&num_bnd), Ok(ValueKind::Primitive(JsType::JsNum(_)))));
+        assert!(matches!(mgr.binding_kind(&str_bnd), Ok(ValueKind::Heap(PtrKind::Str))));
+        assert!(matches!(mgr.binding_kind(&Binding::new("".to_owned())), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_frame_locals() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.alloc(test_utils::make_num(0.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        let (var, ptr) = test_utils::make_str("test");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let locals = mgr.frame_locals();
+        assert_eq!(locals.len(), 1);
+        assert_eq!(locals[0].0, bnd);
+        assert!(matches!(locals[0].2, Some(JsPtrEnum::JsStr(_))));
+    }
+
+    #[test]
+    fn test_current_frame_iter() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        let num_bnd = mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let frame: Vec<_> = mgr.current_frame_iter().collect();
+        assert_eq!(frame.len(), 2);
+        assert!(frame.iter().any(|&(bnd, var)| *bnd == num_bnd && matches!(var.t, JsType::JsNum(_))));
+        assert!(frame.iter().any(|&(bnd, var)| *bnd == str_bnd && matches!(var.t, JsType::JsPtr(_))));
+    }
+
+    #[test]
+    fn test_rebind() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+        let (var, ptr) = test_utils::make_str("test");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        // Hoist the block-local into the function scope, then leave the block
+        mgr.rebind(&bnd, 1).unwrap();
+        assert_eq!(mgr.curr_scope().len(), 0);
+        mgr.pop_scope(None, false).unwrap();
+        assert!(mgr.load(&bnd).is_ok());
+        assert_eq!(mgr.live_object_count(), 1);
+    }
+
+    #[test]
+    fn test_rebind_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+
+        assert!(matches!(mgr.rebind(&bnd, 2), Err(GcError::Scope)));
+        // Moving out of the function scope crosses a function boundary
+        assert!(matches!(mgr.rebind(&bnd, 0), Err(GcError::Scope)));
+        assert!(matches!(mgr.rebind(&Binding::new("".to_owned()), 0), Err(GcError::Load(_))));
+        assert!(mgr.load(&bnd).is_ok());
+    }
+
+    #[test]
+    fn test_closure_captures() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let x_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        let fn_bnd = mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        assert!(mgr.closure_captures(&unique).is_none());
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+
+        let captures = mgr.closure_captures(&unique).unwrap();
+        assert_eq!(captures.len(), 2);
+        assert!(captures.contains(&x_bnd));
+        assert!(captures.contains(&fn_bnd));
+        assert_eq!(mgr.closures.len(), 1);
+    }
+
+    #[test]
+    fn test_closures_iter() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let mut expected = Vec::new();
+        for _ in 0..2 {
+            mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+            let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+            let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+            let unique = fn_var.unique.clone();
+            let fn_bnd = mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+            mgr.pop_scope(Some(unique.clone()), false).unwrap();
+            expected.push((unique, bnd, fn_bnd));
+        }
+
+        let closures: Vec<_> = mgr.closures_iter().collect();
+        assert_eq!(closures.len(), 2);
+        for (unique, bnd, fn_bnd) in expected {
+            let captures = &closures.iter().find(|&&(ref closure, _)| *closure == unique).unwrap().1;
+            assert_eq!(captures.len(), 2);
+            assert!(captures.contains(&bnd));
+            assert!(captures.contains(&fn_bnd));
+        }
+        // Nothing was removed from the manager
+        assert_eq!(mgr.closures.len(), 2);
+    }
+
+    #[test]
+    fn test_detach() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        let (var, ptr) = test_utils::make_str("test");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let detached = mgr.detach(&bnd).unwrap();
+        assert!(mgr.load(&bnd).is_err());
+        mgr.pop_scope(None, true).unwrap();
+        assert!(mgr.alloc_box.borrow().find_id(detached.unique()).is_some());
+
+        drop(detached);
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, true).unwrap();
+        assert_eq!(mgr.live_object_count(), 0);
+    }
+
+    #[test]
+    fn test_detach_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert!(matches!(mgr.detach(&num_bnd), Err(GcError::Load(_))));
+        assert!(mgr.load(&num_bnd).is_ok());
+
+        let (var, ptr) = test_utils::make_str("test");
+        mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        assert!(matches!(mgr.detach(&bnd), Err(GcError::Scope)));
+        assert!(mgr.load(&bnd).is_ok());
+    }
+
+    #[test]
+    fn test_drop_closure() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (shared, shared_ptr) = test_utils::make_str("shared");
+        let shared_unique = shared.unique.clone();
+        mgr.alloc(shared.clone(), Some(shared_ptr.clone())).unwrap();
+
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.alloc(shared, Some(shared_ptr)).unwrap();
+        let (var, ptr) = test_utils::make_str("captured");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+        assert_eq!(mgr.live_object_count(), 3);
+
+        mgr.drop_closure(&unique).unwrap();
+        assert!(mgr.closures.is_empty());
+        // Nothing is freed until the next collection
+        assert_eq!(mgr.live_object_count(), 3);
+
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, true).unwrap();
+        assert_eq!(mgr.live_object_count(), 1);
+        assert!(mgr.alloc_box.borrow().find_id(&shared_unique).is_some());
+        assert!(matches!(mgr.drop_closure(&unique), Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_push_block_and_call_scope() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_block_scope();
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_block_scope();
+        assert_eq!(mgr.current_scope_tag(), ScopeTag::Block);
+        assert!(mgr.load(&bnd).is_ok());
+        mgr.push_call_scope();
+        assert_eq!(mgr.current_scope_tag(), ScopeTag::Call);
+        assert!(mgr.load(&bnd).is_err());
+        assert_eq!(mgr.scopes.len(), 4);
+    }
+
+    #[test]
+    fn test_walk_scopes() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let global = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+        let local = mgr.alloc(test_utils::make_num(2.), None).unwrap();
+
+        let mut visited = Vec::new();
+        let res: Option<()> = mgr.walk_scopes(|depth, tag, bindings| {
+            visited.push((depth, tag.clone(), bindings.len()));
+            None
+        });
+        assert!(res.is_none());
+        assert_eq!(visited, vec![(2, ScopeTag::Block, 1), (1, ScopeTag::Call, 0), (0, ScopeTag::Call, 1)]);
+
+        let depth_of = |bnd: &Binding| mgr.walk_scopes(|depth, _, bindings| {
+            if bindings.contains(bnd) { Some(depth) } else { None }
+        });
+        assert_eq!(depth_of(&local), Some(2));
+        assert_eq!(depth_of(&global), Some(0));
+        assert_eq!(depth_of(&Binding::new("missing".to_string())), None);
+    }
+
+    #[test]
+    fn test_retag_current_scope() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.current_scope_tag(), ScopeTag::Block);
+        assert!(mgr.load(&bnd).is_ok());
+
+        // As a call boundary, the block can no longer see its parent's variables
+        mgr.retag_current_scope(ScopeTag::Call).unwrap();
+        assert_eq!(mgr.current_scope_tag(), ScopeTag::Call);
+        assert!(mgr.load(&bnd).is_err());
+        mgr.retag_current_scope(ScopeTag::Block).unwrap();
+        assert!(mgr.load(&bnd).is_ok());
+    }
+
+    #[test]
+    fn test_retag_current_scope_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        assert!(matches!(mgr.retag_current_scope(ScopeTag::Closure(unique.clone())), Err(GcError::Scope)));
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+
+        mgr.push_closure_scope(&unique).unwrap();
+        assert!(matches!(mgr.retag_current_scope(ScopeTag::Block), Err(GcError::Scope)));
+        assert_eq!(mgr.current_scope_tag(), ScopeTag::Closure(unique));
+    }
+
+    #[test]
+    fn test_prune_closures() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let mut closures = Vec::new();
+        for _ in 0..2 {
+            mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+            let (var, ptr) = test_utils::make_str("captured");
+            mgr.alloc(var, Some(ptr)).unwrap();
+            let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+            closures.push((fn_var.clone(), fn_ptr.clone()));
+            mgr.alloc(fn_var.clone(), Some(fn_ptr)).unwrap();
+            mgr.pop_scope(Some(fn_var.unique), false).unwrap();
+        }
+        // Only the first closure's function is still bound in a live scope
+        let (fn_var, fn_ptr) = closures[0].clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 4);
+
+        assert_eq!(mgr.prune_closures(), 1);
+        assert_eq!(mgr.closures.len(), 1);
+        assert!(mgr.closures.contains_key(&closures[0].0.unique));
+        assert_eq!(mgr.live_object_count(), 2);
+        assert_eq!(mgr.prune_closures(), 0);
+    }
+
+    #[test]
+    fn test_prune_closures_detached() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (var, ptr) = test_utils::make_str("captured");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.pop_scope(Some(fn_var.unique.clone()), false).unwrap();
+        // The host holds on to the closure's function after its binding goes away
+        let fn_bnd = mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        let detached = mgr.detach(&fn_bnd).unwrap();
+        assert_eq!(mgr.live_object_count(), 2);
+
+        assert_eq!(mgr.prune_closures(), 0);
+        assert_eq!(mgr.closures.len(), 1);
+        assert_eq!(mgr.live_object_count(), 2);
+
+        drop(detached);
+        assert_eq!(mgr.prune_closures(), 1);
+        assert_eq!(mgr.live_object_count(), 0);
+    }
+
+    #[test]
+    fn test_scope_enter_hook() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let hook_log = log.clone();
+        mgr.set_scope_enter_hook(move |tag: &ScopeTag, depth| {
+            hook_log.borrow_mut().push((tag.clone(), depth));
+        });
+
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(unique.clone()), false).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_closure_scope(&unique).unwrap();
+
+        assert_eq!(*log.borrow(), vec![(ScopeTag::Call, 1),
+                                       (ScopeTag::Block, 1),
+                                       (ScopeTag::Closure(unique), 2)]);
+    }
+
+    #[test]
+    fn test_on_scope_exit() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        mgr.push_scope(&Exp::Undefined);
+        let x_bnd = mgr.alloc(test_utils::make_num(0.), None).unwrap();
+
+        let (first_log, first_bnd) = (log.clone(), x_bnd.clone());
+        mgr.on_scope_exit(box move |mgr: &mut ScopeManager| {
+            first_log.borrow_mut().push(mgr.load_num(&first_bnd).unwrap());
+        });
+        let (second_log, second_bnd) = (log.clone(), x_bnd.clone());
+        mgr.on_scope_exit(box move |mgr: &mut ScopeManager| {
+            second_log.borrow_mut().push(mgr.load_num(&second_bnd).unwrap());
+            let (mut x, _) = mgr.load(&second_bnd).unwrap();
+            x.t = JsType::JsNum(1.);
+            mgr.store(x, None).unwrap();
+        });
+        assert!(log.borrow().is_empty());
+
+        mgr.pop_scope(None, false).unwrap();
+        // The second hook ran first and its store was visible to the first
+        assert_eq!(*log.borrow(), vec![0., 1.]);
+        assert!(mgr.exit_hooks.is_empty());
+    }
+
+    #[test]
+    fn test_merge_into_parent() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+        let x_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let s_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        mgr.merge_into_parent().unwrap();
+        assert_eq!(mgr.scopes.len(), 2);
+        assert_eq!(mgr.curr_scope().len(), 2);
+        assert!(mgr.load(&x_bnd).is_ok());
+        assert!(mgr.load(&s_bnd).is_ok());
+    }
+
+    #[test]
+    fn test_merge_into_parent_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        assert!(matches!(mgr.merge_into_parent(), Err(GcError::Scope)));
+
+        let x = test_utils::make_num(1.);
+        mgr.alloc(x.clone(), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(x, None).unwrap();
+        assert!(matches!(mgr.merge_into_parent(), Err(GcError::Scope)));
+        assert_eq!(mgr.scopes.len(), 2);
+    }
+
+    #[test]
+    fn test_free_bindings() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("dead");
+        let dead = mgr.alloc(var, Some(ptr)).unwrap();
+        let (str_var, str_ptr) = test_utils::make_str("shared");
+        let kvs = vec![(JsKey::JsSym("s".to_string()), str_var.clone(), Some(str_ptr.clone()))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let obj = mgr.alloc(var, Some(ptr)).unwrap();
+        // Binding the object's child directly shares its heap entry
+        let shared = mgr.alloc(str_var, Some(str_ptr)).unwrap();
+        let num = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert_eq!(mgr.live_object_count(), 3);
+
+        // The shared string is still referenced by the object, so only one entry is condemned,
+        // and it stays in the heap until the next collection
+        assert_eq!(mgr.free_bindings(&[dead.clone(), shared.clone(), num.clone()]), 1);
+        assert_eq!(mgr.live_object_count(), 3);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 2);
+        assert!(mgr.load(&dead).is_err());
+        assert!(mgr.load(&shared).is_err());
+        assert!(mgr.load(&num).is_err());
+        assert!(mgr.load(&obj).is_ok());
+        assert_eq!(mgr.free_bindings(&[dead]), 0);
+    }
+
+    #[test]
+    fn test_free_bindings_detached() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("pinned");
+        let bnd = mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
+        let detached = mgr.detach(&bnd).unwrap();
+        // Bind the detached string again under another name, then free that binding
+        let mut alias = var;
+        alias.binding = Binding::new("s".to_string());
+        let s_bnd = mgr.alloc(alias, Some(ptr)).unwrap();
+
+        assert_eq!(mgr.free_bindings(&[s_bnd]), 0);
+        mgr.collect();
+        assert!(mgr.alloc_box.borrow().find_id(detached.unique()).is_some());
+    }
+
+    #[test]
+    fn test_sweep_ephemerals() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("temp");
+        let temp = mgr.alloc_ephemeral(var, Some(ptr)).unwrap();
+        let num = mgr.alloc_ephemeral(test_utils::make_num(1.), None).unwrap();
+
+        // A temporary that ends up stored in a surviving object
+        let (str_var, str_ptr) = test_utils::make_str("kept");
+        let kvs = vec![(JsKey::JsSym("s".to_string()), str_var.clone(), Some(str_ptr.clone()))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let obj = mgr.alloc(var, Some(ptr)).unwrap();
+        let kept = mgr.alloc_ephemeral(str_var, Some(str_ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 3);
+
+        assert_eq!(mgr.sweep_ephemerals(), 1);
+        assert_eq!(mgr.live_object_count(), 3);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 2);
+        assert!(mgr.load(&temp).is_err());
+        assert!(mgr.load(&num).is_err());
+        assert!(mgr.load(&kept).is_err());
+        assert!(mgr.load(&obj).is_ok());
+        assert_eq!(mgr.sweep_ephemerals(), 0);
+    }
+
+    #[test]
+    fn test_sweep_ephemerals_alias() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("temp");
+        let temp = mgr.alloc_ephemeral(var.clone(), Some(ptr.clone())).unwrap();
+        // The interpreter binds the temporary to a named variable, as for `let s = str[0]`
+        let mut alias = var;
+        alias.binding = Binding::new("s".to_string());
+        let s_bnd = mgr.alloc(alias, Some(ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 1);
+
+        // Only the temporary's own binding is removed
+        assert_eq!(mgr.sweep_ephemerals(), 0);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 1);
+        assert!(mgr.load(&temp).is_err());
+        assert_eq!(mgr.load_str(&s_bnd).unwrap(), "temp");
+    }
+
+    #[test]
+    fn test_rollback() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("before");
+        let before = mgr.alloc(var, Some(ptr)).unwrap();
+        let cp = mgr.checkpoint();
+        let (var, ptr) = test_utils::make_str("after");
+        let after = mgr.alloc(var, Some(ptr)).unwrap();
+        let num = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert_eq!(mgr.live_object_count(), 2);
+
+        mgr.rollback(cp).unwrap();
+        // The rolled back string is freed by the next collection
+        assert_eq!(mgr.live_object_count(), 2);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 1);
+        assert!(mgr.load(&before).is_ok());
+        assert!(matches!(mgr.load(&after), Err(GcError::Load(_))));
+        assert!(matches!(mgr.load(&num), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_rollback_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let cp = mgr.checkpoint();
+        mgr.pop_scope(None, false).unwrap();
+        assert!(matches!(mgr.rollback(cp), Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_flatten_empty_blocks() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        for _ in 0..3 {
+            mgr.push_scope(&Exp::Undefined);
+        }
+        let ran = Rc::new(RefCell::new(false));
+        let flag = ran.clone();
+        mgr.on_scope_exit(box move |_: &mut ScopeManager| *flag.borrow_mut() = true);
+
+        // The non-empty block stops the merge
+        assert_eq!(mgr.flatten_empty_blocks(), 2);
+        assert_eq!(mgr.scopes.len(), 3);
+        assert_eq!(mgr.flatten_empty_blocks(), 0);
+        mgr.pop_scope(None, false).unwrap();
+        assert!(*ran.borrow());
+        assert_eq!(mgr.curr_scope().len(), 1);
+    }
+
+    #[test]
+    fn test_flatten_empty_blocks_fn_boundary() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.flatten_empty_blocks(), 0);
+        assert_eq!(mgr.scopes.len(), 4);
+    }
+
+    #[test]
+    fn test_pop_scope() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.scopes.len(), 2);
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.scopes.len(), 1);
+    }
+
+    #[test]
+    fn test_safe_pop_scope() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+
+        let mut popped = 0;
+        while mgr.safe_pop_scope(None, false).unwrap() {
+            popped += 1;
+        }
+        assert_eq!(popped, 2);
+        assert_eq!(mgr.scopes.len(), 1);
+        assert!(mgr.load(&bnd).is_ok());
+        assert!(!mgr.safe_pop_scope(None, false).unwrap());
+    }
+
+    #[test]
+    fn test_pop_scope_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let res = mgr.pop_scope(None, false);
+        assert!(res.is_err());
+        assert!(matches!(res, Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_pop_closure_scope_returning_closure_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let outer = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(outer.clone()), false).unwrap();
+
+        mgr.push_closure_scope(&outer).unwrap();
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let inner = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        let res = mgr.pop_scope(Some(inner.clone()), false);
+        assert!(matches!(res, Err(GcError::Scope)));
+
+        // Nothing was popped or stashed
+        assert_eq!(mgr.current_closure(), Some(outer.clone()));
+        assert_eq!(mgr.closures.len(), 0);
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+        assert!(mgr.closures.contains_key(&outer));
+    }
+
+    #[test]
+    fn test_alloc() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        assert_eq!(mgr.live_object_count(), 0);
+    }
+
+    #[test]
+    fn test_stack_only() {
+        let mut mgr = ScopeManager::new_stack_only();
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (mut var, _) = mgr.load(&bnd).unwrap();
+        var.t = JsType::JsBool(true);
+        mgr.store(var, None).unwrap();
+        assert!(mgr.load_bool(&bnd).unwrap());
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        mgr.pop_scope(None, true).unwrap();
+        assert_eq!(mgr.live_object_count(), 0);
+    }
+
+    #[test]
+    fn test_stack_only_fail() {
+        let mut mgr = ScopeManager::new_stack_only();
+        let (var, ptr) = test_utils::make_str("test");
+        assert!(matches!(mgr.alloc(var.clone(), Some(ptr)), Err(GcError::PtrAlloc)));
+        assert!(matches!(mgr.alloc(var, None), Err(GcError::PtrAlloc)));
+        assert!(matches!(mgr.alloc_object(Vec::new()), Err(GcError::PtrAlloc)));
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert!(matches!(mgr.load_boxed(&bnd), Err(GcError::PtrAlloc)));
+        assert_eq!(mgr.live_object_count(), 0);
+        assert_eq!(mgr.total_stack_slots(), 1);
+    }
+
+    #[test]
+    fn test_declare() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let bnd = Binding::new("x".to_owned());
+        mgr.declare(bnd.clone()).unwrap();
+        let (mut var, ptr) = mgr.load(&bnd).unwrap();
+        assert!(matches!(var.t, JsType::JsUndef));
+        assert!(ptr.is_none());
+
+        var.t = JsType::JsNum(1.);
+        mgr.store(var, None).unwrap();
+        assert!(f64::abs(mgr.load_num(&bnd).unwrap() - 1.) < 0.0001);
+    }
+
+    #[test]
+    fn test_heap_under_pressure() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_heap_threshold(1);
+        for s in &["a", "b"] {
+            let (var, ptr) = test_utils::make_str(s);
+            mgr.alloc(var, Some(ptr)).unwrap();
+        }
+        assert!(!mgr.heap_under_pressure());
+
+        // Both strings are still in use, so the collection frees nothing
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, true).unwrap();
+        assert_eq!(mgr.last_collection_freed(), 0);
+        assert!(mgr.heap_under_pressure());
+
+        // Under the threshold, a fruitless collection is not pressure
+        mgr.set_heap_threshold(2);
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, true).unwrap();
+        assert!(!mgr.heap_under_pressure());
+    }
+
+    #[test]
+    fn test_auto_gc_interval() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_auto_gc_interval(2);
+        let (var, ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("true".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        // Leak the object's string
+        let (var, mut ptr) = mgr.load(&bnd).unwrap();
+        match *&mut ptr {
+            Some(JsPtrEnum::JsObj(ref mut obj)) => {
+                obj.add_key(&var.unique, JsKey::JsSym("true".to_string()),
+                            test_utils::make_num(-1.), None, &mut *(mgr.alloc_box.borrow_mut()));
+            },
+            _ => unreachable!()
+        }
+        mgr.store(var, ptr).unwrap();
+        assert_eq!(mgr.live_object_count(), 2);
+
+        // The second allocation triggers a collection without any scope being popped
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert_eq!(mgr.live_object_count(), 1);
+        assert_eq!(mgr.last_collection_freed(), 1);
+    }
+
+    #[test]
+    fn test_alloc_global() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (var, ptr) = test_utils::make_str("test");
+        let bnd = mgr.alloc_global(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.curr_scope().len(), 0);
+        assert_eq!(mgr.global_scope().len(), 1);
+        assert_eq!(mgr.load_str(&bnd).unwrap(), "test");
+
+        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.load_str(&bnd).unwrap(), "test");
+    }
+
+    #[test]
+    fn test_alloc_object() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_auto_gc_interval(1);
+        let (var, ptr) = test_utils::make_str("test");
+        let str_unique = var.unique.clone();
+        let entries = vec![(JsKey::JsSym("s".to_string()), var, Some(ptr)),
+                           (JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let bnd = mgr.alloc_object(entries).unwrap();
+
+        // The collection triggered by the allocation sees the string through the object
+        assert_eq!(mgr.live_object_count(), 2);
+        assert_eq!(mgr.own_key_count(&bnd).unwrap(), 2);
+        assert!(mgr.reachable_from(&bnd).contains(&str_unique));
+        assert!(matches!(mgr.binding_kind(&bnd), Ok(ValueKind::Heap(PtrKind::Obj))));
+    }
+
+    #[test]
+    fn test_alloc_object_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let mut entries = Vec::new();
+        for i in 0..5 {
+            let key = JsKey::JsSym(i.to_string());
+            let (var, ptr) = test_utils::make_str("test");
+            // The third entry is a string with no string data to allocate
+            let ptr = if i == 2 { None } else { Some(ptr) };
+            entries.push((key, var, ptr));
+        }
+        assert!(matches!(mgr.alloc_object(entries), Err(GcError::PtrAlloc)));
+        assert_eq!(mgr.live_object_count(), 0);
+        assert_eq!(mgr.total_stack_slots(), 0);
+
+        let (_, ptr) = test_utils::make_str("test");
+        let entries = vec![(JsKey::JsSym("n".to_string()), test_utils::make_num(1.), Some(ptr))];
+        assert!(matches!(mgr.alloc_object(entries), Err(GcError::PtrAlloc)));
+        assert_eq!(mgr.live_object_count(), 0);
+    }
+
+    #[test]
+    fn test_alloc_tracked() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (var, ptr) = test_utils::make_str("test");
+        let (bnd, unique) = mgr.alloc_tracked(var, Some(ptr)).unwrap();
+        assert!(mgr.alloc_box.borrow().find_id(&unique).is_some());
+        assert_eq!(mgr.load(&bnd).unwrap().0.unique, unique);
+    }
+
+    #[test]
+    fn test_heap_liveness() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("s".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.heap_liveness(), (2, 2));
+
+        mgr.clear_object(&bnd).unwrap();
+        assert_eq!(mgr.heap_liveness(), (1, 2));
+        // Nothing was collected
+        assert_eq!(mgr.live_object_count(), 2);
+    }
+
+    #[test]
+    fn test_total_stack_slots() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        assert_eq!(mgr.total_stack_slots(), 0);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.total_stack_slots(), 3);
+        assert_eq!(mgr.live_object_count(), 1);
+        // Only the string survives into the parent scope
+        mgr.pop_scope(None, false).unwrap();
+        assert_eq!(mgr.total_stack_slots(), 2);
+    }
+
+    #[test]
+    fn test_load() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let x = test_utils::make_num(1.);
+        let x_bnd = mgr.alloc(x, None).unwrap();
+        let load = mgr.load(&x_bnd);
+        assert!(load.is_ok());
+        let load = load.unwrap();
+        match load.0.t {
+            JsType::JsNum(n) => assert!(f64::abs(n - 1.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(load.1.is_none());
+    }
+
+    #[test]
+    fn test_load_path() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (var, ptr) = test_utils::make_str("c");
+        let inner_kvs = vec![(JsKey::JsSym("c".to_string()), var, Some(ptr)),
+                             (JsKey::JsSym("d".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(inner_kvs, mgr.alloc_box.clone());
+        let outer_kvs = vec![(JsKey::JsSym("b".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(outer_kvs, mgr.alloc_box.clone());
+        let a_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let keys = [JsKey::JsSym("b".to_string()), JsKey::JsSym("c".to_string())];
+        let (var, ptr) = mgr.load_path(&a_bnd, &keys).unwrap();
+        assert!(matches!(var.t, JsType::JsPtr(JsPtrTag::JsStr)));
+        assert!(matches!(ptr, Some(JsPtrEnum::JsStr(_))));
+
+        let keys = [JsKey::JsSym("b".to_string()), JsKey::JsSym("d".to_string())];
+        let (var, ptr) = mgr.load_path(&a_bnd, &keys).unwrap();
+        assert!(matches!(var.t, JsType::JsNum(_)));
+        assert!(ptr.is_none());
+
+        let (_, ptr) = mgr.load_path(&a_bnd, &[]).unwrap();
+        assert!(matches!(ptr, Some(JsPtrEnum::JsObj(_))));
+    }
+
+    #[test]
+    fn test_load_path_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let kvs = vec![(JsKey::JsSym("b".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let a_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        // Missing key
+        let keys = [JsKey::JsSym("c".to_string())];
+        assert!(matches!(mgr.load_path(&a_bnd, &keys), Err(GcError::Load(_))));
+        // Property access on a number
+        let keys = [JsKey::JsSym("b".to_string()), JsKey::JsSym("c".to_string())];
+        assert!(matches!(mgr.load_path(&a_bnd, &keys), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_load_boxed() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let num_bnd = mgr.alloc(test_utils::make_num(5.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_unique = var.unique.clone();
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let (var, ptr) = mgr.load_boxed(&num_bnd).unwrap();
+        assert!(matches!(var.t, JsType::JsPtr(JsPtrTag::JsObj)));
+        match ptr {
+            JsPtrEnum::JsObj(ref obj) => {
+                let key = JsKey::JsSym(PRIMITIVE_VALUE_KEY.to_string());
+                assert!(matches!(obj.dict.get(&key).map(|var| &var.t), Some(&JsType::JsNum(_))));
+            },
+            _ => panic!("Expected a wrapper object"),
+        }
+        assert_eq!(mgr.live_object_count(), 2);
+
+        // Heap values are returned as they are
+        let (var, _) = mgr.load_boxed(&str_bnd).unwrap();
+        assert_eq!(var.unique, str_unique);
+
+        // The wrapper is condemned by the next ephemeral sweep, and freed by the next collection
+        assert_eq!(mgr.sweep_ephemerals(), 1);
+        assert_eq!(mgr.live_object_count(), 2);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 1);
+    }
+
+    #[test]
+    fn test_load_boxed_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.declare(Binding::new("x".to_string())).unwrap();
+        assert!(matches!(mgr.load_boxed(&Binding::new("x".to_string())), Err(GcError::Load(_))));
+        assert!(matches!(mgr.load_boxed(&Binding::new("y".to_string())), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_get_string_property() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("héllo");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let length = mgr.get_string_property(&bnd, &JsKey::JsStr(JsStrStruct::new("length"))).unwrap();
+        assert!(matches!(length, Some((JsVar { t: JsType::JsNum(n), .. }, None)) if f64::abs(n - 5.) < 0.0001));
+
+        let ch = mgr.get_string_property(&bnd, &JsKey::JsNum(1.)).unwrap();
+        assert!(matches!(ch, Some((_, Some(JsPtrEnum::JsStr(ref s)))) if s.text == "é"));
+        let ch = mgr.get_string_property(&bnd, &JsKey::JsStr(JsStrStruct::new("4"))).unwrap();
+        assert!(matches!(ch, Some((_, Some(JsPtrEnum::JsStr(ref s)))) if s.text == "o"));
+        assert_eq!(mgr.live_object_count(), 3);
 
+        assert!(mgr.get_string_property(&bnd, &JsKey::JsNum(5.)).unwrap().is_none());
+        assert!(mgr.get_string_property(&bnd, &JsKey::JsNum(0.5)).unwrap().is_none());
+        assert!(mgr.get_string_property(&bnd, &JsKey::JsStr(JsStrStruct::new("foo"))).unwrap().is_none());
+        assert!(mgr.get_string_property(&bnd, &JsKey::JsStr(JsStrStruct::new("01"))).unwrap().is_none());
+        assert!(mgr.get_string_property(&bnd, &JsKey::JsStr(JsStrStruct::new("+1"))).unwrap().is_none());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // The character strings are ephemeral, and freed by the collection after the sweep
+        assert_eq!(mgr.sweep_ephemerals(), 2);
+        assert_eq!(mgr.live_object_count(), 3);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 1);
+    }
 
-    use jsrs_common::ast::Exp;
-    use jsrs_common::backend::Backend;
-    use jsrs_common::gc_error::GcError;
-    use jsrs_common::test_utils;
-    use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsType, JsVar};
-    use jsrs_common::types::binding::Binding;
+    #[test]
+    fn test_get_string_property_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let key = JsKey::JsStr(JsStrStruct::new("length"));
+        assert!(matches!(mgr.get_string_property(&bnd, &key), Err(GcError::Load(_))));
+        assert!(matches!(mgr.get_string_property(&Binding::new("x".to_string()), &key), Err(GcError::Load(_))));
+    }
 
     #[test]
-    fn test_push_closure_scope() {
+    fn test_own_keys() {
         let alloc_box = test_utils::make_alloc_box();
         let mut mgr = ScopeManager::new(alloc_box);
-        mgr.push_scope(&Exp::Undefined);
-        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
-        let unique = fn_var.unique.clone();
-        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
-        mgr.pop_scope(Some(unique.clone()), false).unwrap();
-        assert_eq!(mgr.closures.len(), 1);
-        mgr.push_closure_scope(&unique).unwrap();
-        assert_eq!(mgr.closures.len(), 0);
-        mgr.pop_scope(None, false).unwrap();
-        assert_eq!(mgr.closures.len(), 1);
+        let (var, ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("a".to_string()), test_utils::make_num(1.), None),
+                       (JsKey::JsSym("b".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        let keys = mgr.own_keys(&bnd, true).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&JsKey::JsSym("a".to_string())));
+        assert!(keys.contains(&JsKey::JsSym("b".to_string())));
+        assert_eq!(mgr.own_key_count(&bnd).unwrap(), 2);
     }
 
     #[test]
-    fn test_pop_scope() {
+    fn test_own_keys_symbols() {
         let alloc_box = test_utils::make_alloc_box();
         let mut mgr = ScopeManager::new(alloc_box);
-        mgr.push_scope(&Exp::Undefined);
-        assert_eq!(mgr.scopes.len(), 2);
-        mgr.pop_scope(None, false).unwrap();
-        assert_eq!(mgr.scopes.len(), 1);
+        let str_key = JsKey::JsStr(JsStrStruct::new("key"));
+        let sym_key = JsKey::JsSym("key".to_string());
+        let kvs = vec![(str_key.clone(), test_utils::make_num(1.), None),
+                       (sym_key.clone(), test_utils::make_num(2.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        // A symbol and a string with the same name are distinct keys
+        assert_eq!(mgr.own_key_count(&bnd).unwrap(), 2);
+        let keys = mgr.own_keys(&bnd, true).unwrap();
+        assert!(keys.contains(&str_key));
+        assert!(keys.contains(&sym_key));
+        assert_eq!(mgr.own_keys(&bnd, false).unwrap(), vec![str_key.clone()]);
+
+        let (var, _) = mgr.load_path(&bnd, &[str_key]).unwrap();
+        assert!(matches!(var.t, JsType::JsNum(n) if f64::abs(n - 1.) < 0.0001));
+        let (var, _) = mgr.load_path(&bnd, &[sym_key]).unwrap();
+        assert!(matches!(var.t, JsType::JsNum(n) if f64::abs(n - 2.) < 0.0001));
     }
 
     #[test]
-    fn test_pop_scope_fail() {
+    fn test_clear_object() {
         let alloc_box = test_utils::make_alloc_box();
         let mut mgr = ScopeManager::new(alloc_box);
-        let res = mgr.pop_scope(None, false);
-        assert!(res.is_err());
-        assert!(matches!(res, Err(GcError::Scope)));
+        let (var, ptr) = test_utils::make_str("only via obj");
+        let (shared_var, shared_ptr) = test_utils::make_str("shared");
+        let kvs = vec![(JsKey::JsSym("s".to_string()), var, Some(ptr)),
+                       (JsKey::JsSym("t".to_string()), shared_var.clone(), Some(shared_ptr.clone())),
+                       (JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        // Binding the object's child directly shares its heap entry
+        let shared = mgr.alloc(shared_var, Some(shared_ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 3);
+
+        mgr.clear_object(&bnd).unwrap();
+        assert_eq!(mgr.own_key_count(&bnd).unwrap(), 0);
+        // The unshared string isn't freed until the next collection
+        assert_eq!(mgr.live_object_count(), 3);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 2);
+        assert_eq!(mgr.load_str(&shared).unwrap(), "shared");
     }
 
     #[test]
-    fn test_alloc() {
+    fn test_clear_object_fail() {
         let alloc_box = test_utils::make_alloc_box();
         let mut mgr = ScopeManager::new(alloc_box);
-        mgr.alloc(test_utils::make_num(1.), None).unwrap();
-        mgr.push_scope(&Exp::Undefined);
-        mgr.alloc(test_utils::make_num(2.), None).unwrap();
-        assert!(mgr.alloc_box.borrow().is_empty());
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert!(matches!(mgr.clear_object(&num_bnd), Err(GcError::Load(_))));
+        let kvs = vec![(JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.freeze_object(&bnd).unwrap();
+        assert!(matches!(mgr.clear_object(&bnd), Err(GcError::Store(..))));
+        assert_eq!(mgr.own_key_count(&bnd).unwrap(), 1);
     }
 
     #[test]
-    fn test_load() {
+    fn test_own_keys_fail() {
         let alloc_box = test_utils::make_alloc_box();
         let mut mgr = ScopeManager::new(alloc_box);
-        let x = test_utils::make_num(1.);
-        let x_bnd = mgr.alloc(x, None).unwrap();
-        let load = mgr.load(&x_bnd);
-        assert!(load.is_ok());
-        let load = load.unwrap();
-        match load.0.t {
-            JsType::JsNum(n) => assert!(f64::abs(n - 1.) < 0.0001),
-            _ => unreachable!(),
-        }
-        assert!(load.1.is_none());
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        assert!(matches!(mgr.own_keys(&num_bnd, true), Err(GcError::Load(_))));
+        assert!(matches!(mgr.own_keys(&str_bnd, true), Err(GcError::Load(_))));
+        assert!(matches!(mgr.own_key_count(&Binding::new("".to_owned())), Err(GcError::Load(_))));
     }
 
     #[test]
@@ -291,6 +3208,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_load_typed() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let bool_bnd = mgr.alloc(JsVar::new(JsType::JsBool(true)), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        assert!(f64::abs(mgr.load_num(&num_bnd).unwrap() - 1.) < 0.0001);
+        assert_eq!(mgr.load_bool(&bool_bnd).unwrap(), true);
+        assert_eq!(mgr.load_str(&str_bnd).unwrap(), "test");
+    }
+
+    #[test]
+    fn test_load_typed_fail() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let num_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let str_bnd = mgr.alloc(var, Some(ptr)).unwrap();
+
+        assert!(matches!(mgr.load_num(&str_bnd), Err(GcError::Load(_))));
+        assert!(matches!(mgr.load_bool(&num_bnd), Err(GcError::Load(_))));
+        assert!(matches!(mgr.load_str(&num_bnd), Err(GcError::Load(_))));
+        assert!(matches!(mgr.load_num(&Binding::new("".to_owned())), Err(GcError::Load(_))));
+    }
+
     #[test]
     fn test_store() {
         let alloc_box = test_utils::make_alloc_box();
@@ -306,6 +3251,73 @@ mod tests {
         assert!(mgr.store(var, None).is_ok());
     }
 
+    #[test]
+    fn test_store_through_alias() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_obj(Vec::new(), mgr.alloc_box.clone());
+        let a_bnd = mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
+        // Bind a second name to the same object, in a nested scope
+        mgr.push_scope(&Exp::Undefined);
+        let mut alias = var;
+        alias.binding = Binding::new("b".to_string());
+        let b_bnd = mgr.alloc(alias, Some(ptr)).unwrap();
+        assert_eq!(mgr.live_object_count(), 1);
+
+        // Mutate the object through `a`
+        let (var, mut ptr) = mgr.load(&a_bnd).unwrap();
+        if let Some(JsPtrEnum::JsObj(ref mut obj)) = ptr {
+            obj.add_key(&var.unique, JsKey::JsSym("x".to_string()),
+                        test_utils::make_num(1.), None, &mut *(mgr.alloc_box.borrow_mut()));
+        }
+        mgr.store(var, ptr).unwrap();
+
+        // `b` sees the change, and no copy of the object was made
+        assert_eq!(mgr.own_key_count(&b_bnd).unwrap(), 1);
+        assert_eq!(mgr.own_key_count(&a_bnd).unwrap(), 1);
+        assert_eq!(mgr.live_object_count(), 1);
+    }
+
+    #[test]
+    fn test_store_observer() {
+        struct Recorder(Rc<RefCell<Vec<(Binding, JsType, JsType)>>>);
+
+        impl StoreObserver for Recorder {
+            fn on_store(&mut self, bnd: &Binding, old: &JsVar, new: &JsVar) {
+                self.0.borrow_mut().push((bnd.clone(), old.t.clone(), new.t.clone()));
+            }
+        }
+
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        mgr.set_store_observer(box Recorder(log.clone()));
+
+        let (mut var, _) = mgr.load(&bnd).unwrap();
+        var.t = JsType::JsNum(2.);
+        mgr.store(var, None).unwrap();
+        let (mut var, ptr) = test_utils::make_str("test");
+        var.binding = bnd.clone();
+        mgr.reassign(var, Some(ptr)).unwrap();
+        // Failed stores aren't reported
+        assert!(mgr.store(test_utils::make_num(3.), None).is_err());
+
+        {
+            let log = log.borrow();
+            assert_eq!(log.len(), 2);
+            assert!(matches!(log[0], (ref b, JsType::JsNum(old), JsType::JsNum(new))
+                             if *b == bnd && f64::abs(old - 1.) < 0.0001 && f64::abs(new - 2.) < 0.0001));
+            assert!(matches!(log[1], (ref b, JsType::JsNum(_), JsType::JsPtr(JsPtrTag::JsStr)) if *b == bnd));
+        }
+
+        assert!(mgr.take_store_observer().is_some());
+        let mut var = test_utils::make_num(4.);
+        var.binding = bnd.clone();
+        mgr.reassign(var, None).unwrap();
+        assert_eq!(log.borrow().len(), 2);
+    }
+
     #[test]
     fn test_store_fail() {
         let alloc_box = test_utils::make_alloc_box();
@@ -314,6 +3326,105 @@ mod tests {
         assert!(mgr.store(x, None).is_err());
     }
 
+    #[test]
+    fn test_reassign() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let bnd = mgr.alloc(test_utils::make_num(5.), None).unwrap();
+
+        // Primitive to pointer
+        let (mut var, ptr) = test_utils::make_str("test");
+        var.binding = bnd.clone();
+        mgr.reassign(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.load_str(&bnd).unwrap(), "test");
+        assert_eq!(mgr.live_object_count(), 1);
+
+        // Pointer back to primitive frees the string at the next collection
+        let mut var = test_utils::make_num(1.);
+        var.binding = bnd.clone();
+        mgr.reassign(var, None).unwrap();
+        assert!(f64::abs(mgr.load_num(&bnd).unwrap() - 1.) < 0.0001);
+        mgr.collect();
+        assert_eq!(mgr.live_object_count(), 0);
+        assert_eq!(mgr.total_stack_slots(), 1);
+    }
+
+    #[test]
+    fn test_reassign_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        assert!(matches!(mgr.reassign(var, Some(ptr)), Err(GcError::Load(_))));
+
+        let bnd = mgr.alloc(test_utils::make_num(5.), None).unwrap();
+        let (mut var, _) = test_utils::make_str("test");
+        var.binding = bnd.clone();
+        assert!(matches!(mgr.reassign(var, None), Err(GcError::PtrAlloc)));
+        let (_, ptr) = test_utils::make_str("test");
+        let mut var = test_utils::make_num(1.);
+        var.binding = bnd.clone();
+        assert!(matches!(mgr.reassign(var, Some(ptr)), Err(GcError::PtrAlloc)));
+        // The original value is untouched
+        assert!(f64::abs(mgr.load_num(&bnd).unwrap() - 5.) < 0.0001);
+    }
+
+    #[test]
+    fn test_store_batch() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+
+        let x_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(2.);
+        let y = test_utils::make_num(3.);
+
+        mgr.push_scope(&Exp::Undefined);
+        let z_bnd = mgr.alloc(test_utils::make_num(4.), None).unwrap();
+        let (mut z, _) = mgr.load(&z_bnd).unwrap();
+        z.t = JsType::JsNum(5.);
+
+        // Items from different scopes keep their input order in the results
+        let res = mgr.store_batch(vec![(x, None), (y, None), (z, None)]).unwrap();
+        assert_eq!(res.len(), 3);
+        assert!(res[0].is_ok());
+        assert!(matches!(res[1], Err(GcError::Store(..))));
+        assert!(res[2].is_ok());
+        match mgr.load(&x_bnd).unwrap().0.t {
+            JsType::JsNum(n) => assert!(f64::abs(n - 2.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(f64::abs(mgr.load_num(&z_bnd).unwrap() - 5.) < 0.0001);
+
+        // A busy heap fails the whole batch
+        let heap = mgr.alloc_box.clone();
+        let _borrow = heap.borrow();
+        let z = mgr.resolve_var(&z_bnd).unwrap();
+        assert!(matches!(mgr.store_batch(vec![(z, None)]), Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_store_or_declare_local() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        mgr.push_scope(&Exp::Undefined);
+
+        let x_bnd = mgr.store_or_declare_local(test_utils::make_num(1.), None).unwrap();
+        // The binding was declared in the function scope, not the current block
+        assert_eq!(mgr.curr_scope().len(), 0);
+        assert!(mgr.load(&x_bnd).is_ok());
+
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(2.);
+        assert_eq!(mgr.store_or_declare_local(x, None).unwrap(), x_bnd);
+        assert!(f64::abs(mgr.load_num(&x_bnd).unwrap() - 2.) < 0.0001);
+
+        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, false).unwrap();
+        assert!(mgr.load(&x_bnd).is_err());
+    }
+
     #[test]
     fn test_store_to_parent_scope() {
         let alloc_box = test_utils::make_alloc_box();
@@ -418,7 +3529,7 @@ mod tests {
             // Push the obj into the current scope
             let bnd = mgr.alloc(var, Some(ptr)).unwrap();
             // The heap should now have 2 things in it: an object and a string
-            assert_eq!(mgr.alloc_box.borrow().len(), 2);
+            assert_eq!(mgr.live_object_count(), 2);
 
             // Replace the string in the object with something else so it's no longer live
             let copy = mgr.load(&bnd);
@@ -432,7 +3543,7 @@ mod tests {
             }
             mgr.store(var_cp, ptr_cp).unwrap();
             // The heap should still have 2 things in it: an object and a string
-            assert_eq!(mgr.alloc_box.borrow().len(), 2);
+            assert_eq!(mgr.live_object_count(), 2);
 
             // Kill the current scope & give its refs to the parent,
             // allowing the GC to kick in beforehand.
@@ -441,6 +3552,382 @@ mod tests {
         // The object we created above should still exist
         assert_eq!(mgr.curr_scope().len(), 1);
         // But the string it had allocated shouldn't, since we leaked it into the void
-        assert_eq!(mgr.alloc_box.borrow().len(), 1);
+        assert_eq!(mgr.live_object_count(), 1);
+        assert_eq!(mgr.last_collection_freed(), 1);
+    }
+
+    #[test]
+    fn test_traceable_children() {
+        let heap = test_utils::make_alloc_box();
+        let (str_var, str_ptr) = test_utils::make_str("test");
+        let str_unique = str_var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("s".to_string()), str_var, Some(str_ptr.clone())),
+                       (JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let (_, obj_ptr) = test_utils::make_obj(kvs, heap);
+
+        let children = obj_ptr.children();
+        assert_eq!(children.len(), 1);
+        assert!(children.contains(&str_unique));
+        assert!(str_ptr.children().is_empty());
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let str_unique = var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("true".to_string()), var, Some(ptr)),
+                       (JsKey::JsSym("false".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let obj_unique = var.unique.clone();
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        let num_bnd = mgr.alloc(test_utils::make_num(2.), None).unwrap();
+
+        let reachable = mgr.reachable_from(&bnd);
+        assert_eq!(reachable.len(), 2);
+        assert!(reachable.contains(&obj_unique));
+        assert!(reachable.contains(&str_unique));
+        assert!(mgr.reachable_from(&num_bnd).is_empty());
+    }
+
+    #[test]
+    fn test_structural_eq() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let mut objs = Vec::new();
+        for _ in 0..2 {
+            let (var, ptr) = test_utils::make_str("test");
+            let kvs = vec![(JsKey::JsSym("s".to_string()), var, Some(ptr)),
+                           (JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+            let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+            objs.push(mgr.alloc(var, Some(ptr)).unwrap());
+        }
+        let num = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let other_num = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("other");
+        let other_str = mgr.alloc(var, Some(ptr)).unwrap();
+
+        assert!(mgr.structural_eq(&objs[0], &objs[1]).unwrap());
+        assert!(mgr.structural_eq(&objs[0], &objs[0]).unwrap());
+        assert!(mgr.structural_eq(&num, &other_num).unwrap());
+        assert!(!mgr.structural_eq(&objs[0], &num).unwrap());
+        assert!(!mgr.structural_eq(&objs[0], &other_str).unwrap());
+
+        // Change one object's number and they differ
+        let (var, mut ptr) = mgr.load(&objs[1]).unwrap();
+        if let Some(JsPtrEnum::JsObj(ref mut obj)) = ptr {
+            obj.add_key(&var.unique, JsKey::JsSym("n".to_string()),
+                        test_utils::make_num(2.), None, &mut *(mgr.alloc_box.borrow_mut()));
+        }
+        mgr.store(var, ptr).unwrap();
+        assert!(!mgr.structural_eq(&objs[0], &objs[1]).unwrap());
+    }
+
+    #[test]
+    fn test_nan_and_negative_zero() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let nan = mgr.alloc(test_utils::make_num(0.), None).unwrap();
+        let (mut var, _) = mgr.load(&nan).unwrap();
+        var.t = JsType::JsNum(::std::f64::NAN);
+        mgr.store(var, None).unwrap();
+        let other_nan = mgr.alloc(test_utils::make_num(::std::f64::NAN), None).unwrap();
+        let neg_zero = mgr.alloc(test_utils::make_num(-0.), None).unwrap();
+        let zero = mgr.alloc(test_utils::make_num(0.), None).unwrap();
+
+        // Both survive a round trip through the stack unchanged
+        assert!(mgr.load_num(&nan).unwrap().is_nan());
+        let n = mgr.load_num(&neg_zero).unwrap();
+        assert!(n == 0. && n.is_sign_negative());
+        assert!(mgr.load_num(&zero).unwrap().is_sign_positive());
+
+        assert!(mgr.structural_eq(&nan, &other_nan).unwrap());
+        assert!(mgr.structural_eq(&nan, &nan).unwrap());
+        assert!(!mgr.structural_eq(&neg_zero, &zero).unwrap());
+        assert!(mgr.structural_eq(&neg_zero, &neg_zero).unwrap());
+        assert!(!mgr.structural_eq(&nan, &zero).unwrap());
+    }
+
+    #[test]
+    fn test_structural_eq_cycles() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let mut objs = Vec::new();
+        for _ in 0..2 {
+            let (var, ptr) = test_utils::make_obj(Vec::new(), mgr.alloc_box.clone());
+            let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+            // Point the object at itself
+            let (var, mut ptr) = mgr.load(&bnd).unwrap();
+            if let Some(JsPtrEnum::JsObj(ref mut obj)) = ptr {
+                // The object is already in the heap, so there's no new pointer to allocate
+                obj.add_key(&var.unique, JsKey::JsSym("self".to_string()),
+                            var.clone(), None, &mut *(mgr.alloc_box.borrow_mut()));
+            }
+            mgr.store(var, ptr).unwrap();
+            objs.push(bnd);
+        }
+
+        assert!(mgr.structural_eq(&objs[0], &objs[1]).unwrap());
+    }
+
+    #[test]
+    fn test_structural_eq_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let missing = Binding::new("missing".to_string());
+        assert!(matches!(mgr.structural_eq(&bnd, &missing), Err(GcError::Load(_))));
+        assert!(matches!(mgr.structural_eq(&missing, &bnd), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("s".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        // Point the object at itself
+        let (var, mut ptr) = mgr.load(&bnd).unwrap();
+        if let Some(JsPtrEnum::JsObj(ref mut obj)) = ptr {
+            obj.add_key(&var.unique, JsKey::JsSym("self".to_string()),
+                        var.clone(), None, &mut *(mgr.alloc_box.borrow_mut()));
+        }
+        mgr.store(var, ptr).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+
+        let tree = mgr.render_tree();
+        assert!(tree.contains("scope 0 (Call)"));
+        assert!(tree.contains("scope 1 (Block)"));
+        // The object is expanded once, and its cyclic reference points back to it
+        assert!(tree.contains(": object #1\n"));
+        assert_eq!(tree.matches("<object #1>").count(), 1);
+        assert!(tree.contains("\"test\""));
+        assert!(tree.contains("JsNum"));
+    }
+
+    #[test]
+    fn test_immediate_dominator() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        // a -> b1 -> c and a -> b2 -> c
+        let (c_var, c_ptr) = test_utils::make_str("c");
+        let c = c_var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("c".to_string()), c_var.clone(), Some(c_ptr))];
+        let (b1_var, b1_ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let b1 = b1_var.unique.clone();
+        // c is already in the heap, so b2 only needs to refer to it
+        let kvs = vec![(JsKey::JsSym("c".to_string()), c_var, None)];
+        let (b2_var, b2_ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let b2 = b2_var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("b1".to_string()), b1_var, Some(b1_ptr)),
+                       (JsKey::JsSym("b2".to_string()), b2_var, Some(b2_ptr))];
+        let (a_var, a_ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let a = a_var.unique.clone();
+        mgr.alloc(a_var, Some(a_ptr)).unwrap();
+
+        let retainers = mgr.retainers(&c);
+        assert_eq!(retainers.len(), 2);
+        assert!(retainers.contains(&b1));
+        assert!(retainers.contains(&b2));
+        assert_eq!(mgr.retainers(&b1), vec![a.clone()]);
+        assert!(mgr.retainers(&a).is_empty());
+
+        assert_eq!(mgr.immediate_dominator(&c), Some(a.clone()));
+        assert_eq!(mgr.immediate_dominator(&b1), Some(a.clone()));
+        assert_eq!(mgr.immediate_dominator(&a), None);
+
+        // d -> e -> f, with d only held by a detached ref
+        let (f_var, f_ptr) = test_utils::make_str("f");
+        let f = f_var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("f".to_string()), f_var, Some(f_ptr))];
+        let (e_var, e_ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let e = e_var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("e".to_string()), e_var, Some(e_ptr))];
+        let (d_var, d_ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let d = d_var.unique.clone();
+        let d_bnd = mgr.alloc(d_var, Some(d_ptr)).unwrap();
+        let _detached = mgr.detach(&d_bnd).unwrap();
+
+        assert_eq!(mgr.immediate_dominator(&f), Some(e.clone()));
+        assert_eq!(mgr.immediate_dominator(&e), Some(d.clone()));
+        assert_eq!(mgr.immediate_dominator(&d), None);
+    }
+
+    #[test]
+    fn test_heap_histogram() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (var, ptr) = test_utils::make_str("a");
+        let kvs = vec![(JsKey::JsSym("a".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        mgr.alloc(var, Some(ptr)).unwrap();
+        let (var, ptr) = test_utils::make_str("b");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        let (var, ptr) = test_utils::make_fn(&None, &Vec::new());
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("c");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        let _detached = mgr.detach(&bnd).unwrap();
+
+        let histogram = mgr.heap_histogram();
+        assert_eq!(histogram.get(&PtrKind::Str), Some(&3));
+        assert_eq!(histogram.get(&PtrKind::Obj), Some(&1));
+        assert_eq!(histogram.get(&PtrKind::Fn), Some(&1));
+        assert_eq!(histogram.get(&PtrKind::Sym), None);
+    }
+
+    #[test]
+    fn test_for_each_heap_entry() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (var, ptr) = test_utils::make_str("a");
+        let str_unique = var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("a".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let obj_unique = var.unique.clone();
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        // Entries held only by a detached ref are still live
+        let (var, ptr) = test_utils::make_str("b");
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        let detached = mgr.detach(&bnd).unwrap();
+
+        let mut seen = Vec::new();
+        mgr.for_each_heap_entry(|unique, ptr| {
+            let is_obj = matches!(*ptr, JsPtrEnum::JsObj(_));
+            seen.push((unique.clone(), is_obj));
+        });
+        assert_eq!(seen.len(), 3);
+        assert!(seen.contains(&(obj_unique, true)));
+        assert!(seen.contains(&(str_unique, false)));
+        assert!(seen.contains(&(detached.unique().clone(), false)));
+    }
+
+    #[test]
+    fn test_heap_by_tag() {
+        let alloc_box = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let (var, ptr) = test_utils::make_str("untagged");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.set_alloc_tag(7);
+        let (var, ptr) = test_utils::make_str("a");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        let (var, ptr) = test_utils::make_fn(&None, &Vec::new());
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.set_alloc_tag(8);
+        mgr.push_scope(&Exp::Undefined);
+        let (var, ptr) = test_utils::make_str("b");
+        mgr.alloc(var, Some(ptr)).unwrap();
+
+        let counts = mgr.heap_by_tag();
+        assert_eq!(counts.get(&0), Some(&1));
+        assert_eq!(counts.get(&7), Some(&2));
+        assert_eq!(counts.get(&8), Some(&1));
+        assert_eq!(mgr.alloc_tags.len(), 3);
+    }
+
+    #[test]
+    fn test_referencing_scopes() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let unique = var.unique.clone();
+        mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_scope(&Exp::Undefined);
+        // Rebinding an already-allocated pointer shares the heap entry
+        mgr.alloc(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.referencing_scopes(&unique), vec![0, 2]);
+
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let fn_unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(fn_unique.clone()), false).unwrap();
+        assert_eq!(mgr.referencing_scopes(&unique), vec![0]);
+        assert_eq!(mgr.referencing_closures(&unique), vec![fn_unique]);
+    }
+
+    #[test]
+    fn test_freeze_object() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let kvs = vec![(JsKey::JsSym("true".to_string()), test_utils::make_num(1.), None)];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        assert!(!mgr.is_frozen(&bnd));
+        mgr.freeze_object(&bnd).unwrap();
+        assert!(mgr.is_frozen(&bnd));
+
+        // Loads are unaffected, but stores fail
+        let (var, ptr) = mgr.load(&bnd).unwrap();
+        assert!(matches!(mgr.store(var, ptr), Err(GcError::Store(..))));
+    }
+
+    #[test]
+    fn test_deep_freeze() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let str_unique = var.unique.clone();
+        let kvs = vec![(JsKey::JsSym("true".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.deep_freeze(&bnd).unwrap();
+        assert!(mgr.is_frozen(&bnd));
+        assert!(mgr.frozen.contains(&str_unique));
+    }
+
+    #[test]
+    fn test_with_heap() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        mgr.alloc(var, Some(ptr)).unwrap();
+        assert_eq!(mgr.with_heap(|heap| heap.len()), Some(1));
+        assert_eq!(mgr.with_heap_mut(|heap| heap.len()), Some(1));
+
+        let alloc_box = mgr.alloc_box.clone();
+        let _borrow = alloc_box.borrow_mut();
+        assert!(mgr.with_heap(|heap| heap.len()).is_none());
+        assert!(mgr.with_heap_mut(|heap| heap.len()).is_none());
+    }
+
+    #[test]
+    fn test_verify_heap() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (var, ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("true".to_string()), var, Some(ptr))];
+        let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+        mgr.alloc(var, Some(ptr)).unwrap();
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert!(mgr.verify_heap().is_ok());
+    }
+
+    #[test]
+    fn test_verify_heap_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (var, ptr) = test_utils::make_str("test");
+        let unique = var.unique.clone();
+        mgr.alloc(var, Some(ptr)).unwrap();
+
+        // Free the string behind the stack's back
+        mgr.alloc_box.borrow_mut().condemn(unique.clone()).unwrap();
+        mgr.alloc_box.borrow_mut().mark_ptrs();
+        mgr.alloc_box.borrow_mut().sweep_ptrs();
+
+        let res = mgr.verify_heap();
+        assert!(res.is_err());
+        let violations = res.unwrap_err();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0], HeapViolation { unique: unique, kind: ViolationKind::DanglingStackPtr });
     }
 }
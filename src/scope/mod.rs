@@ -1,8 +1,9 @@
 use std::cell::RefCell;
-use std::collections::hash_map::{Entry, HashMap};
+use std::collections::hash_map::{Entry, Values};
 use std::rc::Rc;
 use std::result;
 
+use fnv::FnvHashMap;
 use jsrs_common::alloc_box::AllocBox;
 use jsrs_common::gc_error::{GcError, Result};
 use jsrs_common::types::js_var::{JsPtrEnum, JsType, JsVar};
@@ -17,8 +18,8 @@ use jsrs_common::types::binding::{Binding, UniqueBinding};
 #[derive(Debug)]
 pub struct Scope {
     heap: Rc<RefCell<AllocBox>>,
-    locals: HashMap<Binding, UniqueBinding>,
-    stack: HashMap<UniqueBinding, JsVar>,
+    locals: FnvHashMap<Binding, UniqueBinding>,
+    stack: FnvHashMap<UniqueBinding, JsVar>,
     pub tag: ScopeTag,
 }
 
@@ -49,8 +50,8 @@ impl Scope {
     pub fn new(tag: ScopeTag, heap: &Rc<RefCell<AllocBox>>) -> Scope {
         Scope {
             heap: heap.clone(),
-            locals: HashMap::new(),
-            stack: HashMap::new(),
+            locals: FnvHashMap::default(),
+            stack: FnvHashMap::default(),
             tag: tag,
         }
     }
@@ -61,6 +62,71 @@ impl Scope {
         self.stack.len()
     }
 
+    /// Whether this scope holds no bindings at all.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.locals.is_empty() && self.stack.is_empty()
+    }
+
+    /// Whether this scope is a function boundary, past which variable lookups may not proceed.
+    #[inline]
+    pub fn is_fn_boundary(&self) -> bool {
+        self.tag == ScopeTag::Call || matches!(self.tag, ScopeTag::Closure(_))
+    }
+
+    /// Whether a local binding is defined in this scope.
+    #[inline]
+    pub fn contains(&self, local: &Binding) -> bool {
+        self.locals.contains_key(local)
+    }
+
+    /// Whether a variable with the given unique binding is on this scope's stack.
+    #[inline]
+    pub fn holds(&self, unique: &UniqueBinding) -> bool {
+        self.stack.contains_key(unique)
+    }
+
+    /// Return a reference to the variable with the given unique binding, if it's on this stack.
+    #[inline]
+    pub fn get_var_by_unique(&self, unique: &UniqueBinding) -> Option<&JsVar> {
+        self.stack.get(unique)
+    }
+
+    /// Return a reference to the variable behind a local binding, without touching the heap.
+    pub fn get_var(&self, local: &Binding) -> Option<&JsVar> {
+        self.locals.get(local).and_then(|unique| self.stack.get(unique))
+    }
+
+    /// Return a copy of every local binding defined in this scope.
+    pub fn bindings(&self) -> Vec<Binding> {
+        self.locals.keys().cloned().collect()
+    }
+
+    /// Iterate over every local binding in this scope along with the variable it refers to.
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(&'a Binding, &'a JsVar)> + 'a> {
+        let stack = &self.stack;
+        box self.locals.iter().filter_map(move |(local, unique)| stack.get(unique).map(|var| (local, var)))
+    }
+
+    /// Iterate over every variable on this scope's stack.
+    pub fn vars(&self) -> Values<UniqueBinding, JsVar> {
+        self.stack.values()
+    }
+
+    /// Return the unique bindings of any locals that have no variable on the stack.
+    pub fn dangling_locals(&self) -> Vec<UniqueBinding> {
+        self.locals.values()
+            .filter(|unique| !self.stack.contains_key(unique))
+            .cloned()
+            .collect()
+    }
+
+    /// Reserve space for at least `additional` more variables on this scope's stack.
+    pub fn reserve(&mut self, additional: usize) {
+        self.locals.reserve(additional);
+        self.stack.reserve(additional);
+    }
+
     /// Push a new JsVar onto the stack, and maybe allocate a pointer in the heap.
     pub fn push_var(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
         // Maybe insert the variable's pointer data into the heap
@@ -86,6 +152,25 @@ impl Scope {
         self.stack.insert(var.unique.clone(), var);
     }
 
+    /// Remove a variable from this scope without touching its heap data.
+    pub fn remove_var(&mut self, local: &Binding) -> Option<JsVar> {
+        self.locals.remove(local).and_then(|unique| self.stack.remove(&unique))
+    }
+
+    /// Remove a single local binding from this scope without touching its heap data. The variable
+    /// it refers to is removed and returned only if no other local binding still refers to it.
+    pub fn remove_local(&mut self, local: &Binding) -> Option<JsVar> {
+        let unique = match self.locals.remove(local) {
+            Some(unique) => unique,
+            None => return None,
+        };
+        if self.locals.values().any(|other| *other == unique) {
+            None
+        } else {
+            self.stack.remove(&unique)
+        }
+    }
+
     fn rebind_var(&mut self, local: Binding, unique: UniqueBinding, var: JsVar) {
         self.locals.insert(local, unique.clone());
         self.stack.insert(unique, var);
@@ -108,7 +193,7 @@ impl Scope {
                     _ => Ok((var.clone(), None)),
                 }
             } else { Err(LookupError::Unreachable) }
-        } else if self.tag == ScopeTag::Call || matches!(self.tag, ScopeTag::Closure(_)) {
+        } else if self.is_fn_boundary() {
             // A nonexistent binding in the current scope might require searching
             // the scope tree upwards for the binding. However, if the current
             // scope is a function call, it does not have access to anything from
@@ -120,39 +205,39 @@ impl Scope {
         }
     }
 
+    /// Return a copy of every variable in this scope, along with a copy of its heap data if it is
+    /// a pointer. The order is unspecified, but stays the same as long as the scope is unchanged.
+    pub fn locals_copy(&self) -> Vec<(Binding, JsVar, Option<JsPtrEnum>)> {
+        self.locals.keys()
+            .filter_map(|local| self.get_var_copy(local).ok()
+                        .map(|(var, ptr)| (local.clone(), var, ptr)))
+            .collect()
+    }
+
     /// Try to update a variable that's been allocated.
     pub fn update_var(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> result::Result<(), StoreError> {
         if !self.locals.contains_key(&var.binding) {
-            if self.tag == ScopeTag::Call || matches!(self.tag, ScopeTag::Closure(_)) {
+            if self.is_fn_boundary() {
                 // Variable was not allocated.
                 return Err(StoreError::FnBoundary(var, ptr));
             } else {
                 return Err(StoreError::CheckParent(var, ptr));
             }
         }
-        match var.t {
-            JsType::JsPtr(ref tag) =>
-                if let Some(ref ptr) = ptr {
-                    // If the pointer and its underlying type are not equal, return an error.
-                    if !tag.eq_ptr_type(&ptr) { return Err(StoreError::PtrTypeMismatch); }
-                    // A new root was potentially created
-                    // TODO FIXME? Cloning ptr is potentially expensive
-                    self.heap.borrow_mut().update_ptr(&var.unique, ptr.clone())
-                        .map_err(|_| StoreError::BadStore)?;
-                } else {
-                    return Err(StoreError::PtrTypeMismatch);
-                },
-            _ => {
-                if let Some(_) = ptr {
-                    return Err(StoreError::PtrTypeMismatch);
-                }
-                // A root was potentially removed.
-                // Blindly accept this Result, since we have no information
-                // about the type we're overwriting, and if we fail to condemn
-                // a stack-allocated variable that's completely fine, since the
-                // heap doesn't store those anyway.
-                self.heap.borrow_mut().condemn(var.unique.clone()).ok();
-            },
+        if !matches!(var.t, JsType::JsPtr(_)) {
+            return self.update_primitive(var, ptr);
+        }
+        if let JsType::JsPtr(ref tag) = var.t {
+            if let Some(ref ptr) = ptr {
+                // If the pointer and its underlying type are not equal, return an error.
+                if !tag.eq_ptr_type(&ptr) { return Err(StoreError::PtrTypeMismatch); }
+                // A new root was potentially created
+                // TODO FIXME? Cloning ptr is potentially expensive
+                self.heap.borrow_mut().update_ptr(&var.unique, ptr.clone())
+                    .map_err(|_| StoreError::BadStore)?;
+            } else {
+                return Err(StoreError::PtrTypeMismatch);
+            }
         }
         // Update the variable on the stack
         if let Entry::Occupied(mut view) = self.stack.entry(var.unique.clone()) {
@@ -163,10 +248,33 @@ impl Scope {
         }
     }
 
-    pub fn trigger_gc(&mut self) {
+    /// Fast path for `update_var` when storing a primitive. Primitives never live in the heap, so
+    /// the stack entry is replaced directly, unless it currently holds a pointer.
+    fn update_primitive(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> result::Result<(), StoreError> {
+        if ptr.is_some() {
+            return Err(StoreError::PtrTypeMismatch);
+        }
+        let unique = var.unique.clone();
+        match self.stack.get_mut(&unique) {
+            Some(slot) => {
+                if let JsType::JsPtr(_) = slot.t {
+                    return Err(StoreError::PtrTypeMismatch);
+                }
+                *slot = var;
+                Ok(())
+            },
+            None => Err(StoreError::BadStore),
+        }
+    }
+
+    /// Run a collection, then drop any of this scope's variables whose heap data was freed.
+    /// Returns the number of heap entries the collection freed.
+    pub fn trigger_gc(&mut self) -> usize {
         // The interpreter says we can GC now
+        let before = self.heap.borrow().len();
         self.heap.borrow_mut().mark_ptrs();
         self.heap.borrow_mut().sweep_ptrs();
+        let freed = before - self.heap.borrow().len();
         // Pop any heap-allocated variables we just deleted
         let uniques = self.stack.clone();
         for (unique, var) in uniques {
@@ -184,6 +292,23 @@ impl Scope {
                 self.locals.remove(&local);
             }
         }
+        freed
+    }
+
+    /// Move every variable in this scope into `parent`, primitives included. Fails without moving
+    /// anything if any of this scope's local bindings is already defined in `parent`.
+    pub fn merge_into(&mut self, parent: &mut Scope) -> Result<()> {
+        if self.locals.keys().any(|local| parent.contains(local)) {
+            return Err(GcError::Scope);
+        }
+        for (local, unique) in self.locals.drain() {
+            let var = match self.stack.remove(&unique) {
+                Some(var) => var,
+                None => return Err(GcError::Scope),
+            };
+            parent.rebind_var(local, unique, var);
+        }
+        Ok(())
     }
 
     /// Called when a scope exits. Transfers the stack of this scope to its parent,
@@ -277,6 +402,23 @@ mod tests {
         assert!(copy.is_err());
     }
 
+    #[test]
+    fn test_locals_copy() {
+        let heap = test_utils::make_alloc_box();
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = x.binding.clone();
+        test_scope.push_var(x, Some(x_ptr)).unwrap();
+        test_scope.push_var(test_utils::make_num(1.), None).unwrap();
+
+        let locals = test_scope.locals_copy();
+        assert_eq!(locals.len(), 2);
+        for (bnd, var, ptr) in locals {
+            assert_eq!(bnd, var.binding);
+            assert_eq!(bnd == x_bnd, ptr.is_some());
+        }
+    }
+
     #[test]
     fn test_update_var() {
         let heap = test_utils::make_alloc_box();
@@ -314,6 +456,65 @@ mod tests {
         assert!(matches!(res, Err(StoreError::PtrTypeMismatch)));
     }
 
+    #[test]
+    fn test_merge_into() {
+        let heap = test_utils::make_alloc_box();
+        let mut parent_scope = Scope::new(ScopeTag::Block, &heap);
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        test_scope.push_var(test_utils::make_num(0.), None).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        test_scope.push_var(var, Some(ptr)).unwrap();
+        test_scope.merge_into(&mut parent_scope).unwrap();
+        assert_eq!(parent_scope.len(), 2);
+        assert_eq!(test_scope.len(), 0);
+    }
+
+    #[test]
+    fn test_merge_into_fail() {
+        let heap = test_utils::make_alloc_box();
+        let mut parent_scope = Scope::new(ScopeTag::Block, &heap);
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let var = test_utils::make_num(0.);
+        parent_scope.push_var(var.clone(), None).unwrap();
+        test_scope.push_var(test_utils::make_num(1.), None).unwrap();
+        test_scope.push_var(var, None).unwrap();
+        let res = test_scope.merge_into(&mut parent_scope);
+        assert!(matches!(res, Err(GcError::Scope)));
+        assert_eq!(parent_scope.len(), 1);
+        assert_eq!(test_scope.len(), 2);
+    }
+
+    #[test]
+    fn test_update_var_primitive() {
+        let heap = test_utils::make_alloc_box();
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let x = test_utils::make_num(1.);
+        let x_bnd = x.binding.clone();
+        test_scope.push_var(x, None).unwrap();
+        let (mut update, _) = test_scope.get_var_copy(&x_bnd).unwrap();
+        update.t = JsType::JsNum(2.);
+        assert!(test_scope.update_var(update, None).is_ok());
+        match test_scope.get_var_copy(&x_bnd).unwrap().0.t {
+            JsType::JsNum(n) => assert!(f64::abs(n - 2.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(heap.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_update_var_primitive_over_ptr() {
+        let heap = test_utils::make_alloc_box();
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = x.binding.clone();
+        test_scope.push_var(x, Some(x_ptr)).unwrap();
+        let (mut update, _) = test_scope.get_var_copy(&x_bnd).unwrap();
+        update.t = JsType::JsNum(1.);
+        let res = test_scope.update_var(update, None);
+        assert!(matches!(res, Err(StoreError::PtrTypeMismatch)));
+        assert_eq!(heap.borrow().len(), 1);
+    }
+
     #[test]
     fn test_transfer_stack_no_closure() {
         let heap = test_utils::make_alloc_box();
@@ -0,0 +1,26 @@
+use std::collections::HashSet;
+
+use jsrs_common::types::js_var::{JsPtrEnum, JsType};
+use jsrs_common::types::binding::UniqueBinding;
+
+/// Heap data that can hold pointers to other heap data. The collector's tracing walks the heap
+/// through this trait, so any heap type that implements it contributes its edges.
+pub trait Traceable {
+    /// Return the unique bindings of every heap entry this value refers to directly.
+    fn children(&self) -> HashSet<UniqueBinding>;
+}
+
+impl Traceable for JsPtrEnum {
+    fn children(&self) -> HashSet<UniqueBinding> {
+        match *self {
+            JsPtrEnum::JsObj(ref obj) => {
+                obj.dict.values()
+                    .filter(|var| matches!(var.t, JsType::JsPtr(_)))
+                    .map(|var| var.unique.clone())
+                    .collect()
+            },
+            // Symbols, strings and functions don't refer to other heap entries
+            _ => HashSet::new(),
+        }
+    }
+}